@@ -0,0 +1,98 @@
+//! Mixed read/write throughput of `Cache` and `ShardedCache`.
+//!
+//! cargo bench --bench cache
+
+use std::{
+    sync::Barrier,
+    time::{Duration, Instant},
+};
+
+use rskit::cache::{Cache, ShardedCache};
+
+const KEYS: usize = 10_000;
+const OPS: usize = 200_000;
+
+trait Store: Sync {
+    fn set(&self, k: &str, v: u64);
+    fn get(&self, k: &str) -> Option<u64>;
+}
+
+impl Store for Cache {
+    fn set(&self, k: &str, v: u64) {
+        Cache::set(self, k, v);
+    }
+
+    fn get(&self, k: &str) -> Option<u64> {
+        Cache::get(self, k)
+    }
+}
+
+impl Store for ShardedCache {
+    fn set(&self, k: &str, v: u64) {
+        ShardedCache::set(self, k, v);
+    }
+
+    fn get(&self, k: &str) -> Option<u64> {
+        ShardedCache::get(self, k)
+    }
+}
+
+/// `threads` threads doing `OPS` operations each, `writes` percent of them sets
+fn run<S: Store>(store: &S, threads: usize, writes: usize) -> Duration {
+    let keys = (0..KEYS).map(|i| format!("key:{i}")).collect::<Vec<_>>();
+    for (i, k) in keys.iter().enumerate() {
+        store.set(k, i as u64);
+    }
+    let barrier = Barrier::new(threads + 1);
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            let (keys, barrier) = (&keys, &barrier);
+            s.spawn(move || {
+                // xorshift, cheap and deterministic per thread
+                let mut x = 0x9E37_79B9_7F4A_7C15u64 ^ t as u64;
+                barrier.wait();
+                for _ in 0..OPS {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    let k = &keys[x as usize % KEYS];
+                    if (x >> 32) as usize % 100 < writes {
+                        store.set(k, x);
+                    } else {
+                        std::hint::black_box(store.get(k));
+                    }
+                }
+            });
+        }
+        barrier.wait();
+        let start = Instant::now();
+        // the scope joins every thread before returning
+        start
+    })
+    .elapsed()
+}
+
+fn main() {
+    let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+    println!(
+        "{:>8} {:>7} {:>14} {:>14} {:>8}",
+        "threads", "writes", "cache ops/s", "sharded ops/s", "speedup"
+    );
+    let mut counts = vec![1, 4, cpus.max(8)];
+    counts.dedup();
+    for threads in counts {
+        for writes in [1, 10, 50] {
+            let total = (threads * OPS) as f64;
+            let single = total / run(&Cache::new(), threads, writes).as_secs_f64();
+            let sharded = total / run(&ShardedCache::new(), threads, writes).as_secs_f64();
+            println!(
+                "{:>8} {:>6}% {:>14.0} {:>14.0} {:>7.2}x",
+                threads,
+                writes,
+                single,
+                sharded,
+                sharded / single
+            );
+        }
+    }
+}
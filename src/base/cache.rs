@@ -1,13 +1,944 @@
-use std::{any::Any, collections::HashMap, sync::RwLock};
+use std::{
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    path::Path,
+    sync::{
+        Arc, Mutex, RwLock, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{crypto::aes, sd};
+
+/// Source of time for expiry, replaced by `MockClock` in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// wall clock time matching `now`, for expiry times that outlive the process
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to
+pub struct MockClock {
+    now: Mutex<Instant>,
+    start: Instant,
+    system_start: SystemTime,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        let start = Instant::now();
+        MockClock {
+            now: Mutex::new(start),
+            start,
+            system_start: SystemTime::now(),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            *now += d;
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + (self.now() - self.start)
+    }
+}
+
+/// Which entry goes first when the cache is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// least recently used
+    #[default]
+    Lru,
+    /// least frequently used, ties by least recently used
+    Lfu,
+    /// first inserted
+    Fifo,
+}
+
+/// Why an entry left the cache on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Evicted {
+    Capacity,
+    Expired,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// removed to make room
+    pub evictions: u64,
+    pub expirations: u64,
+    pub len: usize,
+    pub weight: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+type Boxed = Box<dyn Any + Send + Sync>;
+
+type EvictFn = dyn Fn(&str, &(dyn Any + Send + Sync), Evicted) + Send + Sync;
+
+/// A cached load error, see `Cache::negative_ttl`
+#[derive(Clone)]
+struct Failed<E>(E);
+
+/// How to write and read back values of a type registered by `Cache::persist`
+struct Persist {
+    name: String,
+    encode: fn(&(dyn Any + Send + Sync)) -> Result<Vec<u8>, String>,
+    decode: fn(&[u8]) -> Result<Boxed, String>,
+}
+
+fn encode<V: Serialize + Any>(v: &(dyn Any + Send + Sync)) -> Result<Vec<u8>, String> {
+    match v.downcast_ref::<V>() {
+        Some(v) => sd::to_bin(v),
+        None => Err(format!("not a {}", std::any::type_name::<V>())),
+    }
+}
+
+fn decode<V: DeserializeOwned + Any + Send + Sync>(b: &[u8]) -> Result<Boxed, String> {
+    sd::from_bin::<V>(b).map(|v| Box::new(v) as Boxed)
+}
+
+fn epoch_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    /// name given to `persist`
+    kind: String,
+    value: Vec<u8>,
+    /// wall clock expiry in milliseconds since the unix epoch
+    expires: Option<u64>,
+    weight: usize,
+}
+
+const NONCE_LEN: usize = 12;
+
+/// A key bound to the type of its value, so `get_typed` can not ask for the wrong type,
+/// `const NAME: CacheKey<String> = CacheKey::new("name")` or `CacheKey::from(format!(..))`
+pub struct CacheKey<V> {
+    key: std::borrow::Cow<'static, str>,
+    _value: std::marker::PhantomData<fn() -> V>,
+}
+
+impl<V> CacheKey<V> {
+    pub const fn new(key: &'static str) -> Self {
+        CacheKey {
+            key: std::borrow::Cow::Borrowed(key),
+            _value: std::marker::PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl<V> From<String> for CacheKey<V> {
+    fn from(key: String) -> Self {
+        CacheKey {
+            key: std::borrow::Cow::Owned(key),
+            _value: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<V> Clone for CacheKey<V> {
+    fn clone(&self) -> Self {
+        CacheKey {
+            key: self.key.clone(),
+            _value: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<V> std::fmt::Debug for CacheKey<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CacheKey<{}>({})", std::any::type_name::<V>(), self.key)
+    }
+}
+
+/// separator between a namespace and its keys
+pub const NAMESPACE_SEPARATOR: &str = ":";
+
+enum Lookup<V> {
+    Fresh(V),
+    /// expired but within the stale window
+    Stale(V),
+    /// a live entry of another type
+    Other,
+    Miss,
+}
+
+struct Entry {
+    value: Boxed,
+    /// a cached `Failed` load, a miss for `get`
+    failed: bool,
+    expires: Option<Instant>,
+    weight: usize,
+    inserted: u64,
+    accessed: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    /// expired and past the stale window, see `Cache::stale_while_revalidate`
+    fn dead(&self, now: Instant, stale: Duration) -> bool {
+        self.expires.is_some_and(|e| e + stale <= now)
+    }
+
+    fn rank(&self, eviction: Eviction) -> (u64, u64) {
+        let accessed = self.accessed.load(Ordering::Relaxed);
+        match eviction {
+            Eviction::Lru => (accessed, 0),
+            Eviction::Lfu => (self.hits.load(Ordering::Relaxed), accessed),
+            Eviction::Fifo => (self.inserted, 0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    map: HashMap<String, Entry>,
+    weight: usize,
+    /// keys by expiry and insertion, so expired entries are found without a scan
+    expiry: BTreeMap<(Instant, u64), String>,
+    /// keys by eviction rank, only kept with a capacity, locked by readers too
+    ranks: Option<Mutex<BTreeMap<(u64, u64), String>>>,
+    eviction: Eviction,
+}
+
+impl Store {
+    fn insert(&mut self, k: &str, entry: Entry) {
+        self.remove(k);
+        if let Some(expires) = entry.expires {
+            self.expiry.insert((expires, entry.inserted), k.to_string());
+        }
+        if let Some(ref mut ranks) = self.ranks {
+            let rank = entry.rank(self.eviction);
+            lock(ranks).insert(rank, k.to_string());
+        }
+        self.weight += entry.weight;
+        self.map.insert(k.to_string(), entry);
+    }
+
+    fn remove(&mut self, k: &str) -> Option<Entry> {
+        let entry = self.map.remove(k)?;
+        self.weight -= entry.weight;
+        if let Some(expires) = entry.expires {
+            self.expiry.remove(&(expires, entry.inserted));
+        }
+        if let Some(ref mut ranks) = self.ranks {
+            lock(ranks).remove(&entry.rank(self.eviction));
+        }
+        Some(entry)
+    }
+
+    /// record a hit of `x`, keeping its rank in order
+    fn touch(&self, x: &Entry, tick: u64) {
+        match self.ranks {
+            Some(ref ranks) if self.eviction != Eviction::Fifo => {
+                let mut ranks = lock(ranks);
+                let old = x.rank(self.eviction);
+                x.accessed.store(tick, Ordering::Relaxed);
+                x.hits.fetch_add(1, Ordering::Relaxed);
+                if let Some(k) = ranks.remove(&old) {
+                    ranks.insert(x.rank(self.eviction), k);
+                }
+            }
+            _ => {
+                x.accessed.store(tick, Ordering::Relaxed);
+                x.hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn expired(&mut self, now: Instant, stale: Duration) -> Vec<(String, Entry)> {
+        let mut expired = vec![];
+        while let Some((&(expires, _), _)) = self.expiry.first_key_value()
+            && expires + stale <= now
+        {
+            if let Some((_, k)) = self.expiry.pop_first()
+                && let Some(x) = self.remove(&k)
+            {
+                expired.push((k, x));
+            }
+        }
+        expired
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        let ranks = self.ranks.as_mut()?;
+        lock(ranks).first_key_value().map(|(_, k)| k.clone())
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.expiry.clear();
+        self.weight = 0;
+        if let Some(ref mut ranks) = self.ranks {
+            lock(ranks).clear();
+        }
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match m.lock() {
+        Ok(g) => g,
+        Err(e) => e.into_inner(),
+    }
+}
 
 pub struct Cache {
-    store: RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>,
+    store: RwLock<Store>,
+    ttl: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    capacity: Option<usize>,
+    on_evict: Option<Arc<EvictFn>>,
+    negative_ttl: Option<Duration>,
+    stale: Duration,
+    loading: Mutex<HashMap<String, Arc<dyn Any + Send + Sync>>>,
+    persist: HashMap<TypeId, Persist>,
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
 }
 
 impl Cache {
     pub fn new() -> Self {
         Cache {
-            store: RwLock::new(HashMap::new()),
+            store: RwLock::new(Store::default()),
+            ttl: None,
+            clock: Arc::new(SystemClock),
+            capacity: None,
+            on_evict: None,
+            negative_ttl: None,
+            stale: Duration::ZERO,
+            loading: Mutex::new(HashMap::new()),
+            persist: HashMap::new(),
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    /// default ttl of `set`, entries never expire without it
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// the ttl given to `ttl`
+    pub(crate) fn default_ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// max total weight, an entry weighs 1 unless set by `set_weighted`,
+    /// so without weights this is the max number of entries
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        if let Ok(store) = self.store.get_mut() {
+            store.ranks = Some(Mutex::new(BTreeMap::new()));
+        }
+        self
+    }
+
+    /// policy to make room when full, `Eviction::Lru` by default
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        if let Ok(store) = self.store.get_mut() {
+            store.eviction = eviction;
+        }
+        self
+    }
+
+    /// called with key, value and reason when an entry is evicted or expires,
+    /// outside the cache lock so it may use the cache, not for `remove`,
+    /// `remove_prefix` and `clear`
+    pub fn on_evict<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, &(dyn Any + Send + Sync), Evicted) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Arc::new(f));
+        self
+    }
+
+    /// cache load errors of `get_or_try_insert_with` for `ttl`, not cached by default
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// keep expired entries for `window` more, `get_or_try_insert_with` returns them
+    /// while reloading in the background, `get` treats them as missing
+    pub fn stale_while_revalidate(mut self, window: Duration) -> Self {
+        self.stale = window;
+        self
+    }
+
+    /// include values of type `V` in `save` and read them back in `load`,
+    /// `name` is stored with them so register the same names when loading,
+    /// it must stay the same across versions, e.g. `persist::<User>("user")`
+    pub fn persist<V>(mut self, name: &str) -> Self
+    where
+        V: Serialize + DeserializeOwned + Any + Send + Sync + Clone,
+    {
+        self.persist.insert(
+            TypeId::of::<V>(),
+            Persist {
+                name: name.to_string(),
+                encode: encode::<V>,
+                decode: decode::<V>,
+            },
+        );
+        self
+    }
+
+    pub fn set<V>(&self, k: &str, v: V)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.insert(k, v, self.ttl, 1);
+    }
+
+    /// like `set` with its own ttl instead of the default
+    pub fn set_with_ttl<V>(&self, k: &str, v: V, ttl: Duration)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.insert(k, v, Some(ttl), 1);
+    }
+
+    /// like `set` with a weight counted against `capacity`, e.g. the size in bytes,
+    /// a value heavier than the capacity is not cached and removes the key
+    pub fn set_weighted<V>(&self, k: &str, v: V, weight: usize)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.insert(k, v, self.ttl, weight);
+    }
+
+    fn insert<V>(&self, k: &str, v: V, ttl: Option<Duration>, weight: usize)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.insert_boxed(k, Box::new(v), ttl, weight, false);
+    }
+
+    fn insert_boxed(&self, k: &str, v: Boxed, ttl: Option<Duration>, weight: usize, failed: bool) {
+        if self.capacity.is_some_and(|c| weight > c) {
+            log::warn!("cache entry {k} weighs {weight}, more than the capacity");
+            // the caller replaced the value, the old one must not be served any more
+            if let Ok(ref mut store) = self.store.write() {
+                store.remove(k);
+            }
+            return;
+        }
+        let now = self.clock.now();
+        let entry = Entry {
+            value: v,
+            failed,
+            expires: ttl.map(|ttl| now + ttl),
+            weight,
+            inserted: self.tick(),
+            accessed: AtomicU64::new(self.tick()),
+            hits: AtomicU64::new(0),
+        };
+        let mut evicted = vec![];
+        let mut expired = vec![];
+        if let Ok(ref mut store) = self.store.write() {
+            store.remove(k);
+            if let Some(capacity) = self.capacity {
+                if store.weight + weight > capacity {
+                    expired = store.expired(now, self.stale);
+                }
+                while store.weight + weight > capacity
+                    && let Some(victim) = store.victim()
+                    && let Some(x) = store.remove(&victim)
+                {
+                    evicted.push((victim, x));
+                }
+            }
+            store.insert(k, entry);
+        }
+        self.evicted(expired, Evicted::Expired);
+        self.evicted(evicted, Evicted::Capacity);
+    }
+
+    fn tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn evicted(&self, entries: Vec<(String, Entry)>, reason: Evicted) {
+        let counter = match reason {
+            Evicted::Capacity => &self.evictions,
+            Evicted::Expired => &self.expirations,
+        };
+        counter.fetch_add(entries.len() as u64, Ordering::Relaxed);
+        if let Some(ref f) = self.on_evict {
+            for (k, x) in entries {
+                f(&k, x.value.as_ref(), reason);
+            }
+        }
+    }
+
+    /// `None` if missing, expired or not a `V`, expired entries are removed
+    pub fn get<V>(&self, k: &str) -> Option<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        match self.lookup(k) {
+            Lookup::Fresh(v) => Some(v),
+            Lookup::Other => {
+                log::warn!("cache entry {k} is not a {}", std::any::type_name::<V>());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn lookup<V>(&self, k: &str) -> Lookup<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        let now = self.clock.now();
+        let mut dead = false;
+        if let Ok(ref store) = self.store.read() {
+            match store.map.get(k) {
+                Some(x) if !x.dead(now, self.stale) => {
+                    if let Some(v) = x.value.downcast_ref::<V>().cloned() {
+                        if x.expired(now) {
+                            self.misses.fetch_add(1, Ordering::Relaxed);
+                            return Lookup::Stale(v);
+                        }
+                        store.touch(x, self.tick());
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        return Lookup::Fresh(v);
+                    }
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return match x.failed {
+                        true => Lookup::Miss,
+                        false => Lookup::Other,
+                    };
+                }
+                Some(_) => dead = true,
+                None => (),
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if dead {
+            let entry = match self.store.write() {
+                Ok(mut store) if store.map.get(k).is_some_and(|x| x.dead(now, self.stale)) => {
+                    store.remove(k)
+                }
+                _ => None,
+            };
+            if let Some(x) = entry {
+                self.evicted(vec![(k.to_string(), x)], Evicted::Expired);
+            }
+        }
+        Lookup::Miss
+    }
+
+    /// The cached value, or the result of `f` which is cached when ok.
+    /// Concurrent misses of a key wait for a single call of `f`.
+    /// Errors are cached for `negative_ttl` if set, and stale values are returned
+    /// while `f` reloads in the background, see `stale_while_revalidate`.
+    /// A failed reload is logged and keeps the stale value.
+    pub async fn get_or_try_insert_with<V, E, F, Fut>(
+        self: &Arc<Self>,
+        k: &str,
+        f: F,
+    ) -> Result<V, E>
+    where
+        V: Any + Send + Sync + Clone,
+        E: Any + Send + Sync + Clone + Debug,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        match self.lookup::<V>(k) {
+            Lookup::Fresh(v) => return Ok(v),
+            Lookup::Stale(v) => {
+                if !self.is_loading(k) {
+                    let cache = self.clone();
+                    let k = k.to_string();
+                    tokio::spawn(async move {
+                        let _ = cache.load_with(&k, f, true).await;
+                    });
+                }
+                return Ok(v);
+            }
+            Lookup::Other | Lookup::Miss => (),
+        }
+        if let Some(e) = self.failed::<E>(k) {
+            return Err(e);
+        }
+        self.load_with(k, f, false).await
+    }
+
+    /// a cached load error, the miss is already counted
+    fn failed<E: Any + Clone>(&self, k: &str) -> Option<E> {
+        self.negative_ttl?;
+        let now = self.clock.now();
+        let store = self.store.read().ok()?;
+        let x = store.map.get(k).filter(|x| x.failed && !x.expired(now))?;
+        x.value.downcast_ref::<Failed<E>>().map(|f| f.0.clone())
+    }
+
+    fn is_loading(&self, k: &str) -> bool {
+        self.loading.lock().is_ok_and(|l| l.contains_key(k))
+    }
+
+    /// `revalidate` keeps the current value on error
+    async fn load_with<V, E, F, Fut>(&self, k: &str, f: F, revalidate: bool) -> Result<V, E>
+    where
+        V: Any + Send + Sync + Clone,
+        E: Any + Send + Sync + Clone + Debug,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let cell = match self.loading.lock() {
+            Ok(mut loading) => {
+                let cell = loading.get(k).and_then(|c| {
+                    c.clone()
+                        .downcast::<tokio::sync::OnceCell<Result<V, E>>>()
+                        .ok()
+                });
+                match cell {
+                    Some(cell) => cell,
+                    None => {
+                        let cell = Arc::new(tokio::sync::OnceCell::new());
+                        loading.insert(k.to_string(), cell.clone());
+                        cell
+                    }
+                }
+            }
+            Err(_) => Arc::new(tokio::sync::OnceCell::new()),
+        };
+        let result = cell
+            .get_or_init(|| async {
+                let result = f().await;
+                match result {
+                    Ok(ref v) => self.set(k, v.clone()),
+                    Err(ref e) if revalidate => {
+                        log::error!(
+                            "revalidate cache entry {k} error: {e:?}, keeping the stale value"
+                        );
+                    }
+                    Err(ref e) => {
+                        if let Some(ttl) = self.negative_ttl {
+                            self.insert_boxed(k, Box::new(Failed(e.clone())), Some(ttl), 1, true);
+                        }
+                    }
+                }
+                result
+            })
+            .await
+            .clone();
+        if let Ok(mut loading) = self.loading.lock()
+            && loading
+                .get(k)
+                .is_some_and(|c| std::ptr::addr_eq(Arc::as_ptr(c), Arc::as_ptr(&cell)))
+        {
+            loading.remove(k);
+        }
+        result
+    }
+
+    /// time left before `k` expires, `None` if missing or without ttl
+    pub fn expires_in(&self, k: &str) -> Option<Duration> {
+        let now = self.clock.now();
+        match self.store.read() {
+            Ok(store) => store
+                .map
+                .get(k)
+                .filter(|x| !x.expired(now))
+                .and_then(|x| x.expires)
+                .map(|e| e - now),
+            Err(_) => None,
+        }
+    }
+
+    /// not an eviction, `on_evict` is not called and stats don't count it
+    pub fn remove(&self, k: &str) -> bool {
+        match self.store.write() {
+            Ok(mut store) => store.remove(k).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn set_typed<V>(&self, k: &CacheKey<V>, v: V)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.set(k.key(), v);
+    }
+
+    pub fn set_typed_with_ttl<V>(&self, k: &CacheKey<V>, v: V, ttl: Duration)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.set_with_ttl(k.key(), v, ttl);
+    }
+
+    pub fn get_typed<V>(&self, k: &CacheKey<V>) -> Option<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.get(k.key())
+    }
+
+    /// keys prefixed by `<name>:`, e.g. `cache.namespace("user").set("1", u)` sets `user:1`
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace {
+            cache: self,
+            prefix: format!("{name}{NAMESPACE_SEPARATOR}"),
+        }
+    }
+
+    /// remove every key starting with `prefix`, returns how many, like `remove`
+    /// without `on_evict` and stats
+    pub fn remove_prefix(&self, prefix: &str) -> usize {
+        match self.store.write() {
+            Ok(mut store) => {
+                let keys = store
+                    .map
+                    .keys()
+                    .filter(|k| k.starts_with(prefix))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                keys.iter().filter(|k| store.remove(k).is_some()).count()
+            }
+            Err(_) => 0,
+        }
+    }
+
+    /// remove every entry, like `remove` without `on_evict` and stats
+    pub fn clear(&self) {
+        if let Ok(mut store) = self.store.write() {
+            store.clear();
+        }
+    }
+
+    /// number of entries, including expired ones not removed yet
+    pub fn len(&self) -> usize {
+        self.store.read().map(|s| s.map.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (len, weight) = self
+            .store
+            .read()
+            .map(|s| (s.map.len(), s.weight))
+            .unwrap_or_default();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            len,
+            weight,
+        }
+    }
+
+    /// remove expired entries, returns how many
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let expired = match self.store.write() {
+            Ok(mut store) => store.expired(now, self.stale),
+            Err(_) => vec![],
+        };
+        let n = expired.len();
+        self.evicted(expired, Evicted::Expired);
+        n
+    }
+
+    /// Write live entries of the types registered by `persist` to `path` with their
+    /// wall clock expiry, encrypted with AES-256-GCM when `key` is given.
+    /// Entries of other types are skipped, returns how many were written.
+    pub fn save<P: AsRef<Path>>(&self, path: P, key: Option<&[u8]>) -> Result<usize, String> {
+        let now = self.clock.now();
+        let system_now = self.clock.system_now();
+        let entries = match self.store.read() {
+            Ok(store) => store
+                .map
+                .iter()
+                .filter(|(_, x)| !x.expired(now))
+                .filter_map(|(k, x)| {
+                    let persist = self.persist.get(&(*x.value).type_id())?;
+                    match (persist.encode)(x.value.as_ref()) {
+                        Ok(value) => Some(SnapshotEntry {
+                            key: k.clone(),
+                            kind: persist.name.clone(),
+                            value,
+                            expires: x.expires.map(|e| epoch_millis(system_now + (e - now))),
+                            weight: x.weight,
+                        }),
+                        Err(e) => {
+                            log::error!("serialize cache entry {k} error: {e}");
+                            None
+                        }
+                    }
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut data = sd::to_bin(&entries)?;
+        if let Some(key) = key {
+            check_key(key)?;
+            let mut sealed = aes::aes_gcm_nonce_256();
+            sealed.extend(aes::encrypt_aes_gcm_256(key, &sealed, &data));
+            data = sealed;
+        }
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, path).map_err(|e| e.to_string())?;
+        Ok(entries.len())
+    }
+
+    /// Read entries written by `save`, skipping those of types not registered by `persist`
+    /// and those that expired meanwhile, e.g. while the process was down.
+    /// Returns how many were restored.
+    pub fn load<P: AsRef<Path>>(&self, path: P, key: Option<&[u8]>) -> Result<usize, String> {
+        let mut data = std::fs::read(path).map_err(|e| e.to_string())?;
+        if let Some(key) = key {
+            check_key(key)?;
+            if data.len() < NONCE_LEN {
+                return Err("cache snapshot too short".to_string());
+            }
+            let (nonce, sealed) = data.split_at(NONCE_LEN);
+            data = aes::try_decrypt_aes_gcm_256(key, nonce, sealed)
+                .map_err(|_| "decrypt cache snapshot error, wrong key".to_string())?;
+        }
+        let entries: Vec<SnapshotEntry> = sd::from_bin(&data)?;
+        let now = epoch_millis(self.clock.system_now());
+        let mut n = 0;
+        for entry in entries {
+            let ttl = match entry.expires {
+                Some(expires) if expires <= now => continue,
+                Some(expires) => Some(Duration::from_millis(expires - now)),
+                None => None,
+            };
+            let Some(persist) = self.persist.values().find(|p| p.name == entry.kind) else {
+                log::warn!("cache entry {} of unknown type {}", entry.key, entry.kind);
+                continue;
+            };
+            match (persist.decode)(&entry.value) {
+                Ok(v) => {
+                    self.insert_boxed(&entry.key, v, ttl, entry.weight, false);
+                    n += 1;
+                }
+                Err(e) => log::error!("deserialize cache entry {} error: {e}", entry.key),
+            }
+        }
+        Ok(n)
+    }
+
+    /// purge expired entries every `every` on a tokio task, which ends when the cache is dropped
+    pub fn sweeper(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let cache: Weak<Cache> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match cache.upgrade() {
+                    Some(cache) => {
+                        cache.purge_expired();
+                    }
+                    None => break,
+                }
+            }
+        })
+    }
+}
+
+/// A view of a `Cache` whose keys are prefixed by the namespace name, see `Cache::namespace`
+pub struct Namespace<'a> {
+    cache: &'a Cache,
+    prefix: String,
+}
+
+impl Namespace<'_> {
+    fn key(&self, k: &str) -> String {
+        format!("{}{}", self.prefix, k)
+    }
+
+    /// a nested namespace, `user` then `session` gives `user:session:`
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace {
+            cache: self.cache,
+            prefix: format!("{}{name}{NAMESPACE_SEPARATOR}", self.prefix),
         }
     }
 
@@ -15,20 +946,654 @@ impl Cache {
     where
         V: Any + Send + Sync + Clone,
     {
-        if let Ok(ref mut map) = self.store.write() {
-            map.insert(k.to_string(), Box::new(v));
+        self.cache.set(&self.key(k), v);
+    }
+
+    pub fn set_with_ttl<V>(&self, k: &str, v: V, ttl: Duration)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.cache.set_with_ttl(&self.key(k), v, ttl);
+    }
+
+    pub fn get<V>(&self, k: &str) -> Option<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.cache.get(&self.key(k))
+    }
+
+    pub fn set_typed<V>(&self, k: &CacheKey<V>, v: V)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.set(k.key(), v);
+    }
+
+    pub fn get_typed<V>(&self, k: &CacheKey<V>) -> Option<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.get(k.key())
+    }
+
+    pub fn remove(&self, k: &str) -> bool {
+        self.cache.remove(&self.key(k))
+    }
+
+    /// remove every key of the namespace, returns how many
+    pub fn clear(&self) -> usize {
+        self.cache.remove_prefix(&self.prefix)
+    }
+}
+
+fn check_key(key: &[u8]) -> Result<(), String> {
+    match key.len() {
+        32 => Ok(()),
+        n => Err(format!("cache snapshot key must be 32 bytes, got {n}")),
+    }
+}
+
+/// default number of shards of `ShardedCache`
+pub const SHARDS: usize = 16;
+
+/// `Cache` split into shards by key hash, each with its own lock,
+/// so writes only block readers of the same shard
+pub struct ShardedCache {
+    shards: Vec<Arc<Cache>>,
+    hasher: RandomState,
+}
+
+impl Default for ShardedCache {
+    fn default() -> Self {
+        ShardedCache::new()
+    }
+}
+
+impl ShardedCache {
+    pub fn new() -> Self {
+        ShardedCache::with_shards(SHARDS, Cache::new)
+    }
+
+    /// `n` shards built by `f`, `capacity` set in `f` is per shard
+    pub fn with_shards<F: Fn() -> Cache>(n: usize, f: F) -> Self {
+        ShardedCache {
+            shards: (0..n.max(1)).map(|_| Arc::new(f())).collect(),
+            hasher: RandomState::new(),
         }
     }
 
+    /// the shard holding `k`
+    pub fn shard(&self, k: &str) -> &Arc<Cache> {
+        let i = self.hasher.hash_one(k) as usize % self.shards.len();
+        &self.shards[i]
+    }
+
+    pub fn shards(&self) -> &[Arc<Cache>] {
+        &self.shards
+    }
+
+    pub fn set<V>(&self, k: &str, v: V)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.shard(k).set(k, v);
+    }
+
+    pub fn set_with_ttl<V>(&self, k: &str, v: V, ttl: Duration)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.shard(k).set_with_ttl(k, v, ttl);
+    }
+
+    pub fn set_weighted<V>(&self, k: &str, v: V, weight: usize)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.shard(k).set_weighted(k, v, weight);
+    }
+
     pub fn get<V>(&self, k: &str) -> Option<V>
     where
         V: Any + Send + Sync + Clone,
     {
-        if let Ok(ref map) = self.store.read() {
-            if let Some(ref x) = map.get(k) {
-                return x.downcast_ref::<V>().cloned();
+        self.shard(k).get(k)
+    }
+
+    /// see `Cache::get_or_try_insert_with`
+    pub async fn get_or_try_insert_with<V, E, F, Fut>(&self, k: &str, f: F) -> Result<V, E>
+    where
+        V: Any + Send + Sync + Clone,
+        E: Any + Send + Sync + Clone + Debug,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        self.shard(k).get_or_try_insert_with(k, f).await
+    }
+
+    pub fn expires_in(&self, k: &str) -> Option<Duration> {
+        self.shard(k).expires_in(k)
+    }
+
+    pub fn remove(&self, k: &str) -> bool {
+        self.shard(k).remove(k)
+    }
+
+    pub fn set_typed<V>(&self, k: &CacheKey<V>, v: V)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.set(k.key(), v);
+    }
+
+    pub fn get_typed<V>(&self, k: &CacheKey<V>) -> Option<V>
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.get(k.key())
+    }
+
+    pub fn remove_prefix(&self, prefix: &str) -> usize {
+        self.shards.iter().map(|s| s.remove_prefix(prefix)).sum()
+    }
+
+    pub fn clear(&self) {
+        self.shards.iter().for_each(|s| s.clear());
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_empty())
+    }
+
+    /// stats of all shards added up
+    pub fn stats(&self) -> CacheStats {
+        self.shards
+            .iter()
+            .map(|s| s.stats())
+            .fold(CacheStats::default(), |a, b| CacheStats {
+                hits: a.hits + b.hits,
+                misses: a.misses + b.misses,
+                evictions: a.evictions + b.evictions,
+                expirations: a.expirations + b.expirations,
+                len: a.len + b.len,
+                weight: a.weight + b.weight,
+            })
+    }
+
+    pub fn purge_expired(&self) -> usize {
+        self.shards.iter().map(|s| s.purge_expired()).sum()
+    }
+
+    /// see `Cache::sweeper`
+    pub fn sweeper(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let cache: Weak<ShardedCache> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match cache.upgrade() {
+                    Some(cache) => {
+                        cache.purge_expired();
+                    }
+                    None => break,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::new()
+            .ttl(Duration::from_secs(60))
+            .clock(clock.clone());
+        cache.set("a", 1);
+        cache.set_with_ttl("b", 2, Duration::from_secs(10));
+        assert_eq!(Some(Duration::from_secs(10)), cache.expires_in("b"));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Some(1), cache.get::<i32>("a"));
+        assert_eq!(None, cache.get::<i32>("b"));
+        assert_eq!(1, cache.len());
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(None, cache.get::<i32>("a"));
+        assert!(cache.is_empty());
+
+        let cache = Cache::new().clock(clock.clone());
+        cache.set("forever", "x");
+        cache.set_with_ttl("short", "y", Duration::from_secs(1));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(None, cache.expires_in("forever"));
+        assert_eq!(1, cache.purge_expired());
+        assert_eq!(Some("x"), cache.get::<&str>("forever"));
+    }
+
+    #[test]
+    fn test_eviction_index() {
+        for eviction in [Eviction::Lru, Eviction::Lfu, Eviction::Fifo] {
+            let clock = Arc::new(MockClock::new());
+            let cache = Cache::new()
+                .capacity(10)
+                .eviction(eviction)
+                .clock(clock.clone());
+            for i in 0..100 {
+                cache.set_with_ttl(&i.to_string(), i, Duration::from_secs(i % 7 + 1));
+                cache.get::<i32>(&(i / 2).to_string());
+                if i % 10 == 9 {
+                    clock.advance(Duration::from_secs(1));
+                    cache.remove(&(i - 3).to_string());
+                }
+            }
+            let store = cache.store.read().unwrap();
+            let ranks = store.ranks.as_ref().unwrap().lock().unwrap();
+            assert_eq!(9, store.map.len());
+            assert_eq!(store.map.len(), ranks.len());
+            assert_eq!(store.map.len(), store.expiry.len());
+            assert!(ranks.values().all(|k| store.map.contains_key(k)));
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = Cache::new().capacity(2);
+        cache.set("a", 1);
+        cache.set("b", 2);
+        cache.get::<i32>("a");
+        cache.set("c", 3);
+        assert_eq!(None, cache.get::<i32>("b"));
+        assert_eq!(Some(1), cache.get::<i32>("a"));
+
+        let cache = Cache::new().capacity(2).eviction(Eviction::Fifo);
+        cache.set("a", 1);
+        cache.set("b", 2);
+        cache.get::<i32>("a");
+        cache.set("c", 3);
+        assert_eq!(None, cache.get::<i32>("a"));
+        assert_eq!(Some(2), cache.get::<i32>("b"));
+
+        let cache = Cache::new().capacity(2).eviction(Eviction::Lfu);
+        cache.set("a", 1);
+        cache.set("b", 2);
+        cache.get::<i32>("a");
+        cache.get::<i32>("a");
+        cache.get::<i32>("b");
+        cache.set("c", 3);
+        assert_eq!(None, cache.get::<i32>("b"));
+        assert_eq!(Some(1), cache.get::<i32>("a"));
+        // replacing a key does not evict
+        cache.set("c", 4);
+        assert_eq!(2, cache.len());
+    }
+
+    #[test]
+    fn test_weight_and_stats() {
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let e = evicted.clone();
+        let clock = Arc::new(MockClock::new());
+        let cache = Cache::new()
+            .capacity(10)
+            .clock(clock.clone())
+            .on_evict(move |k, v, reason| {
+                let v = v.downcast_ref::<String>().cloned().unwrap_or_default();
+                e.lock().unwrap().push((k.to_string(), v, reason));
+            });
+        cache.set_weighted("a", "aaaa".to_string(), 4);
+        cache.set_weighted("b", "bbbb".to_string(), 4);
+        cache.set_weighted("c", "cccccc".to_string(), 6);
+        assert_eq!(None, cache.get::<String>("a"));
+        assert_eq!(Some("bbbb".to_string()), cache.get::<String>("b"));
+        assert_eq!(Some("cccccc".to_string()), cache.get::<String>("c"));
+        cache.set_weighted("big", "x".to_string(), 11);
+        assert_eq!(None, cache.get::<String>("big"));
+
+        // b is the least recently used
+        cache.set_with_ttl("d", "d".to_string(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(None, cache.get::<String>("d"));
+        // a wrong type is a miss
+        assert_eq!(None, cache.get::<i32>("c"));
+
+        assert_eq!(
+            vec![
+                ("a".to_string(), "aaaa".to_string(), Evicted::Capacity),
+                ("b".to_string(), "bbbb".to_string(), Evicted::Capacity),
+                ("d".to_string(), "d".to_string(), Evicted::Expired),
+            ],
+            *evicted.lock().unwrap()
+        );
+        let stats = cache.stats();
+        assert_eq!(2, stats.hits);
+        assert_eq!(4, stats.misses);
+        assert_eq!(2, stats.evictions);
+        assert_eq!(1, stats.expirations);
+        assert_eq!(1, stats.len);
+        assert_eq!(6, stats.weight);
+        assert!((stats.hit_rate() - 2.0 / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_oversized_overwrite() {
+        let cache = Cache::new().capacity(10);
+        cache.set_weighted("a", "small".to_string(), 4);
+        cache.set_with_ttl("b", "b".to_string(), Duration::from_secs(60));
+        cache.set_weighted("a", "huge".to_string(), 11);
+        assert_eq!(None, cache.get::<String>("a"));
+        assert_eq!(1, cache.len());
+        assert_eq!(1, cache.stats().weight);
+
+        // the index holds no trace of the removed entry
+        cache.set_weighted("c", "c".to_string(), 9);
+        assert_eq!(Some("b".to_string()), cache.get::<String>("b"));
+        assert_eq!(Some("c".to_string()), cache.get::<String>("c"));
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert() {
+        let cache = Arc::new(Cache::new());
+        let calls = Arc::new(AtomicU64::new(0));
+        let mut tasks = vec![];
+        for _ in 0..10 {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            tasks.push(tokio::spawn(async move {
+                cache
+                    .get_or_try_insert_with("user:1", move || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok::<_, String>("alice".to_string())
+                    })
+                    .await
+            }));
+        }
+        for task in tasks {
+            assert_eq!(Ok("alice".to_string()), task.await.unwrap());
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!(Some("alice".to_string()), cache.get::<String>("user:1"));
+        assert!(!cache.is_loading("user:1"));
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Arc::new(
+            Cache::new()
+                .negative_ttl(Duration::from_secs(5))
+                .clock(clock.clone()),
+        );
+        let calls = Arc::new(AtomicU64::new(0));
+        let load = |calls: Arc<AtomicU64>| {
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err::<i32, _>("db down".to_string())
+            }
+        };
+        for _ in 0..3 {
+            let r = cache.get_or_try_insert_with("k", load(calls.clone())).await;
+            assert_eq!(Err("db down".to_string()), r);
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        // one miss per call
+        assert_eq!((0, 3), (cache.stats().hits, cache.stats().misses));
+        assert_eq!(None, cache.get::<i32>("k"));
+        // a plain miss, not an entry of another type
+        assert!(matches!(cache.lookup::<i32>("k"), Lookup::Miss));
+
+        clock.advance(Duration::from_secs(5));
+        let _ = cache.get_or_try_insert_with("k", load(calls.clone())).await;
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        // without negative_ttl every call loads
+        let cache = Arc::new(Cache::new());
+        for _ in 0..2 {
+            let _ = cache.get_or_try_insert_with("k", load(calls.clone())).await;
+        }
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let clock = Arc::new(MockClock::new());
+        let cache = Arc::new(
+            Cache::new()
+                .ttl(Duration::from_secs(10))
+                .stale_while_revalidate(Duration::from_secs(60))
+                .clock(clock.clone()),
+        );
+        let load = |v: i32| move || async move { Ok::<_, String>(v) };
+        assert_eq!(Ok(1), cache.get_or_try_insert_with("k", load(1)).await);
+
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(None, cache.get::<i32>("k"));
+        assert_eq!(Ok(1), cache.get_or_try_insert_with("k", load(2)).await);
+        for _ in 0..50 {
+            if cache.get::<i32>("k").is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(Some(2), cache.get::<i32>("k"));
+
+        // past the stale window it is a plain miss
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(Ok(3), cache.get_or_try_insert_with("k", load(3)).await);
+
+        // a failed reload keeps the stale value, even with negative caching
+        let cache = Arc::new(
+            Cache::new()
+                .ttl(Duration::from_secs(10))
+                .stale_while_revalidate(Duration::from_secs(60))
+                .negative_ttl(Duration::from_secs(60))
+                .clock(clock.clone()),
+        );
+        assert_eq!(Ok(1), cache.get_or_try_insert_with("k", load(1)).await);
+        clock.advance(Duration::from_secs(15));
+        let fail = || async { Err::<i32, _>("db down".to_string()) };
+        assert_eq!(Ok(1), cache.get_or_try_insert_with("k", fail).await);
+        // let the background reload run and fail
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!cache.is_loading("k"));
+        assert_eq!(Ok(1), cache.get_or_try_insert_with("k", fail).await);
+    }
+
+    #[test]
+    fn test_sharded() {
+        let cache = ShardedCache::with_shards(4, || Cache::new().capacity(100));
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        cache.set(&format!("{t}:{i}"), i);
+                    }
+                });
             }
+        });
+        assert_eq!(400, cache.len() + cache.stats().evictions as usize);
+        let k = (0..100)
+            .map(|i| format!("0:{i}"))
+            .find(|k| cache.get::<i32>(k).is_some())
+            .unwrap();
+        // the value lives in its own shard only
+        let shard = cache.shard(&k);
+        assert!(shard.get::<i32>(&k).is_some());
+        let others = cache.shards().iter().filter(|s| !Arc::ptr_eq(s, shard));
+        assert!(others.clone().all(|s| s.get::<i32>(&k).is_none()));
+        assert!(cache.remove(&k));
+        assert_eq!(None, cache.get::<i32>(&k));
+
+        // keys spread over the shards, and len and clear cover all of them
+        let cache = ShardedCache::with_shards(4, Cache::new);
+        for i in 0..100 {
+            cache.set(&i.to_string(), i);
+        }
+        let lens = cache.shards().iter().map(|s| s.len()).collect::<Vec<_>>();
+        assert!(lens.iter().filter(|n| **n > 0).count() > 1);
+        assert_eq!(100, lens.iter().sum::<usize>());
+        assert_eq!(100, cache.len());
+        assert_eq!(100, cache.stats().len);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(cache.shards().iter().all(|s| s.is_empty()));
+
+        let cache = ShardedCache::new();
+        cache.set("a", 1);
+        assert_eq!(Some(1), cache.get::<i32>("a"));
+        assert_eq!(SHARDS, cache.shards().len());
+        assert_eq!(1, cache.stats().hits);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct User {
+            name: String,
+        }
+        let clock = Arc::new(MockClock::new());
+        let new = || {
+            Cache::new()
+                .clock(clock.clone())
+                .persist::<String>("string")
+                .persist::<User>("user")
+        };
+        let cache = new();
+        cache.set("name", "alice".to_string());
+        let user = User {
+            name: "bob".to_string(),
+        };
+        cache.set_with_ttl("user", user.clone(), Duration::from_secs(60));
+        cache.set_with_ttl("gone", "x".to_string(), Duration::from_secs(10));
+        // not registered, or not serializable at all
+        cache.set("n", 1u32);
+        cache.set("lock", Arc::new(Mutex::new(1)));
+
+        let path = std::env::temp_dir().join("rskit-cache.snapshot");
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(2, cache.save(&path, None).unwrap());
+
+        let restored = new();
+        assert_eq!(2, restored.load(&path, None).unwrap());
+        assert_eq!(Some("alice".to_string()), restored.get::<String>("name"));
+        assert_eq!(Some(user.clone()), restored.get::<User>("user"));
+        assert_eq!(Some(Duration::from_secs(40)), restored.expires_in("user"));
+        assert_eq!(None, restored.get::<u32>("n"));
+
+        // only String registered, users are skipped
+        let partial = Cache::new().persist::<String>("string");
+        assert_eq!(1, partial.load(&path, None).unwrap());
+
+        // entries follow the registered name, not the rust type name
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        struct Renamed {
+            name: String,
         }
-        None
+        let renamed = Cache::new().persist::<Renamed>("user");
+        assert_eq!(1, renamed.load(&path, None).unwrap());
+        assert_eq!("bob", renamed.get::<Renamed>("user").unwrap().name);
+
+        let key = aes::aes_gcm_key_256();
+        assert_eq!(2, cache.save(&path, Some(&key)).unwrap());
+        assert!(
+            !std::fs::read(&path)
+                .unwrap()
+                .windows(5)
+                .any(|w| w == b"alice")
+        );
+        assert!(new().load(&path, Some(&aes::aes_gcm_key_256())).is_err());
+        assert!(new().load(&path, Some(&key[..16])).is_err());
+        let restored = new();
+        assert_eq!(2, restored.load(&path, Some(&key)).unwrap());
+        assert_eq!(Some(user), restored.get::<User>("user"));
+
+        // the user expires while the process is down, the name has no ttl
+        clock.advance(Duration::from_secs(50));
+        let restored = new();
+        assert_eq!(1, restored.load(&path, Some(&key)).unwrap());
+        assert_eq!(Some("alice".to_string()), restored.get::<String>("name"));
+        assert_eq!(None, restored.get::<User>("user"));
+    }
+
+    #[test]
+    fn test_typed_keys() {
+        const NAME: CacheKey<String> = CacheKey::new("name");
+        let cache = Cache::new();
+        cache.set_typed(&NAME, "alice".to_string());
+        assert_eq!(Some("alice".to_string()), cache.get_typed(&NAME));
+
+        let age = CacheKey::<u8>::from(format!("age:{}", 1));
+        cache.set_typed_with_ttl(&age, 30, Duration::from_secs(60));
+        assert_eq!(Some(30), cache.get_typed(&age));
+        assert_eq!("age:1", age.key());
+        assert_eq!("CacheKey<u8>(age:1)", format!("{:?}", age.clone()));
+
+        let sharded = ShardedCache::new();
+        sharded.set_typed(&NAME, "bob".to_string());
+        assert_eq!(Some("bob".to_string()), sharded.get_typed(&NAME));
+    }
+
+    #[test]
+    fn test_namespace() {
+        let cache = Cache::new().capacity(10);
+        let users = cache.namespace("user");
+        let orders = cache.namespace("order");
+        users.set("1", "alice");
+        users.set("2", "bob");
+        orders.set("1", 100);
+        assert_eq!(Some("alice"), users.get::<&str>("1"));
+        assert_eq!(Some(100), orders.get::<i32>("1"));
+        assert_eq!(Some("bob"), cache.get::<&str>("user:2"));
+
+        let sessions = users.namespace("session");
+        sessions.set("1", true);
+        assert_eq!(Some(true), cache.get::<bool>("user:session:1"));
+
+        assert_eq!(3, users.clear());
+        assert_eq!(None, users.get::<&str>("1"));
+        assert_eq!(1, cache.len());
+        assert_eq!(1, cache.stats().weight);
+        assert!(orders.remove("1"));
+
+        cache.set("a", 1);
+        cache.set("b", 2);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(0, cache.stats().weight);
+
+        let sharded = ShardedCache::new();
+        for i in 0..20 {
+            sharded.set(&format!("user:{i}"), i);
+        }
+        sharded.set("order:1", 1);
+        assert_eq!(20, sharded.remove_prefix("user:"));
+        assert_eq!(1, sharded.len());
+        sharded.clear();
+        assert!(sharded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sweeper() {
+        let cache = Arc::new(Cache::new());
+        cache.set_with_ttl("a", 1, Duration::from_millis(10));
+        let sweeper = cache.sweeper(Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.is_empty());
+
+        drop(cache);
+        tokio::time::timeout(Duration::from_secs(1), sweeper)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
@@ -1,19 +1,33 @@
 use std::{
     env,
-    sync::{Arc, LazyLock, RwLock},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Duration,
 };
 
-use config::{Config, File};
+use config::{Config, ConfigError, File, ValueKind};
 use notify::{Event, RecommendedWatcher, Watcher};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::sync::watch;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+use crate::{
+    LogConfig,
+    crypto::secret,
+    logs::{self, Redactor},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Settings {
     pub app: App,
+    #[serde(default)]
+    pub log: Option<LogConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct App {
     pub version: String,
 }
@@ -21,9 +35,33 @@ pub struct App {
 pub static AUTO_CONFIG: LazyLock<Arc<RwLock<Option<Settings>>>> =
     LazyLock::new(|| Arc::new(RwLock::new(load())));
 
+/// extensions of the supported config formats
+pub const CONFIG_EXTENSIONS: [&str; 7] = ["toml", "yaml", "yml", "json", "json5", "ini", "ron"];
+
+/// `app.toml`, or `app` with another supported extension if only that exists
 fn path() -> String {
     let current_dir = env::current_dir().expect("failed to get current directory");
-    current_dir.join("app.toml").to_string_lossy().to_string()
+    let path = CONFIG_EXTENSIONS
+        .iter()
+        .map(|ext| current_dir.join(format!("app.{ext}")))
+        .find(|p| p.is_file())
+        .unwrap_or(current_dir.join("app.toml"));
+    path.to_string_lossy().to_string()
+}
+
+/// files of a config directory such as `conf.d` with a supported extension, sorted by name
+pub fn dir_files<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| CONFIG_EXTENSIONS.contains(&e))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
 }
 
 fn load() -> Option<Settings> {
@@ -31,7 +69,7 @@ fn load() -> Option<Settings> {
         .add_source(File::with_name(&path()))
         .build()
     {
-        Ok(c) => match c.try_deserialize() {
+        Ok(c) => match deserialize(c, None) {
             Ok(s) => s,
             Err(e) => {
                 log::error!("deserialize error: {e:?}");
@@ -44,16 +82,58 @@ fn load() -> Option<Settings> {
         }
     }
 }
+static NOTIFIER: LazyLock<Notifier<Settings>> = LazyLock::new(Notifier::new);
+
+/// called with old and new settings and the changed keys after `AUTO_CONFIG` is refreshed
+pub fn subscribe<F>(f: F)
+where
+    F: Fn(&Settings, &Settings, &[Change]) + Send + Sync + 'static,
+{
+    NOTIFIER.subscribe(f);
+}
+
+/// reject a refresh of `AUTO_CONFIG` by returning an error, the old settings are kept
+pub fn validate<F>(f: F)
+where
+    F: Fn(&Settings) -> Result<(), String> + Send + Sync + 'static,
+{
+    NOTIFIER.validate(f);
+}
+
 fn refresh() {
     if let Some(s) = load() {
-        *AUTO_CONFIG.write().unwrap() = Some(s);
+        if let Err(e) = NOTIFIER.check(&s) {
+            log::error!("invalid config: {e}");
+            return;
+        }
+        if let Some(ref log) = s.log {
+            match log.directives() {
+                Ok(d) => logs::set_directives(d),
+                Err(e) => log::error!("log directives error: {e}"),
+            }
+        }
+        let new = s.clone();
+        let (old, changes) = {
+            let mut config = match AUTO_CONFIG.write() {
+                Ok(c) => c,
+                Err(e) => e.into_inner(),
+            };
+            let old = config.replace(s).unwrap_or_default();
+            let changes = diff(&old, &new);
+            (old, changes)
+        };
+        // the lock is released, subscribers may read or refresh `AUTO_CONFIG`
+        NOTIFIER.notify(&old, &new, &changes);
     }
 }
 
 fn show() {
     match AUTO_CONFIG.read() {
         Ok(config) => {
-            println!("config: {:?}", config);
+            println!(
+                "config: {}",
+                Redactor::default().redact(&format!("{:?}", config))
+            );
         }
         Err(e) => {
             log::error!("config error: {e:?}");
@@ -61,51 +141,824 @@ fn show() {
     }
 }
 fn watch() {
+    match watcher(Path::new(&path()), DEBOUNCE, on_change) {
+        Ok(_guard) => loop {
+            std::thread::park();
+        },
+        Err(e) => log::error!("watcher error: {e:?}"),
+    }
+}
+
+fn on_change() {
+    log::info!("refreshing configuration ...");
+    refresh();
+    show();
+}
+
+/// show the config and refresh `AUTO_CONFIG` on changes, blocks the calling thread
+pub fn init() {
+    show();
+    watch();
+}
+
+/// like `init` without blocking, watching on the current tokio runtime until the guard is dropped
+pub fn init_async() -> Result<WatchGuard, notify::Error> {
+    show();
+    async_watcher(Path::new(&path()), DEBOUNCE, on_change)
+}
+
+/// quiet time after the last event before reloading, editors write in several steps
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watch the directory of `path` rather than the file, so atomic renames (vim)
+/// and symlink swaps (k8s configmaps) are seen, and call `f` once events stop for `debounce`.
+/// Dropping the returned guard stops everything, including a pending call.
+pub fn watcher<F>(path: &Path, debounce: Duration, f: F) -> Result<WatchGuard, notify::Error>
+where
+    F: Fn() + Send + 'static,
+{
     let (tx, rx) = std::sync::mpsc::channel();
-    let result: Result<RecommendedWatcher, _> = Watcher::new(
-        tx,
-        notify::Config::default().with_poll_interval(Duration::from_secs(3)),
-    );
-    match result {
-        Ok(mut watcher) => {
-            match watcher.watch(
-                std::path::Path::new(&path()),
-                notify::RecursiveMode::NonRecursive,
-            ) {
-                Ok(_) => (),
-                Err(e) => log::error!("watch error: {e:?}"),
+    let watcher = dir_watcher(path, move || {
+        let _ = tx.send(());
+    })?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    std::thread::spawn(move || {
+        use std::sync::mpsc::RecvTimeoutError;
+        while rx.recv().is_ok() {
+            loop {
+                match rx.recv_timeout(debounce) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            if stopped.load(Ordering::SeqCst) {
+                return;
             }
+            f();
+        }
+    });
+    Ok(WatchGuard {
+        _watcher: watcher,
+        stop,
+    })
+}
 
+/// Like `watcher`, debouncing on a tokio task instead of a thread, needs a tokio runtime.
+pub fn async_watcher<F>(path: &Path, debounce: Duration, f: F) -> Result<WatchGuard, notify::Error>
+where
+    F: Fn() + Send + 'static,
+{
+    let handle = tokio::runtime::Handle::try_current()
+        .map_err(|e| notify::Error::generic(&e.to_string()))?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let watcher = dir_watcher(path, move || {
+        let _ = tx.send(());
+    })?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    handle.spawn(async move {
+        while rx.recv().await.is_some() {
             loop {
-                match rx.recv() {
-                    Ok(Ok(Event {
-                        kind: notify::event::EventKind::Modify(_),
-                        ..
-                    })) => {
-                        log::info!("refreshing configuration ...");
-                        refresh();
-                        show();
-                    }
-                    Err(e) => log::error!("recv error: {e:?}"),
-                    _ => (),
+                match tokio::time::timeout(debounce, rx.recv()).await {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => return,
+                    Err(_) => break,
                 }
             }
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            f();
         }
-        Err(e) => {
-            log::error!("watcher error: {e:?}");
+    });
+    Ok(WatchGuard {
+        _watcher: watcher,
+        stop,
+    })
+}
+
+fn dir_watcher<F>(path: &Path, f: F) -> Result<RecommendedWatcher, notify::Error>
+where
+    F: Fn() + Send + 'static,
+{
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name().map(|n| n.to_os_string());
+    // a symlinked file changes when anything it points through is swapped
+    let symlink = path.is_symlink();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event)
+            if matches!(
+                event.kind,
+                notify::EventKind::Modify(_)
+                    | notify::EventKind::Create(_)
+                    | notify::EventKind::Remove(_)
+            ) && (symlink
+                || event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().map(|n| n.to_os_string()) == name)) =>
+        {
+            f()
         }
+        Err(e) => log::error!("watch error: {e:?}"),
+        _ => (),
+    })?;
+    watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+/// A changed key, nested keys are joined by `.`, e.g. `app.version`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// the keys whose values differ, arrays are compared as a whole
+pub fn diff<T: Serialize>(old: &T, new: &T) -> Vec<Change> {
+    let mut changes = vec![];
+    match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(old), Ok(new)) => diff_value("", Some(&old), Some(&new), &mut changes),
+        (Err(e), _) | (_, Err(e)) => log::error!("diff config error: {e:?}"),
     }
+    changes
 }
 
-pub fn init() {
-    show();
-    watch();
+fn diff_value(key: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for k in keys {
+                let path = if key.is_empty() {
+                    k.to_string()
+                } else {
+                    format!("{key}.{k}")
+                };
+                diff_value(&path, old.get(k), new.get(k), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(Change {
+            key: key.to_string(),
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => (),
+    }
+}
+
+type Subscriber<T> = Box<dyn Fn(&T, &T, &[Change]) + Send + Sync>;
+type Validator<T> = Box<dyn Fn(&T) -> Result<(), String> + Send + Sync>;
+
+struct Notifier<T> {
+    subscribers: RwLock<Vec<Subscriber<T>>>,
+    validator: RwLock<Option<Validator<T>>>,
+}
+
+impl<T: Serialize> Notifier<T> {
+    fn new() -> Self {
+        Notifier {
+            subscribers: RwLock::new(vec![]),
+            validator: RwLock::new(None),
+        }
+    }
+
+    fn subscribe<F>(&self, f: F)
+    where
+        F: Fn(&T, &T, &[Change]) + Send + Sync + 'static,
+    {
+        if let Ok(mut s) = self.subscribers.write() {
+            s.push(Box::new(f));
+        }
+    }
+
+    fn validate<F>(&self, f: F)
+    where
+        F: Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    {
+        if let Ok(mut v) = self.validator.write() {
+            *v = Some(Box::new(f));
+        }
+    }
+
+    fn check(&self, new: &T) -> Result<(), String> {
+        match self.validator.read() {
+            Ok(v) => v.as_ref().map_or(Ok(()), |f| f(new)),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// subscribers are only called when there are changes
+    fn notify(&self, old: &T, new: &T, changes: &[Change]) {
+        if !changes.is_empty()
+            && let Ok(s) = self.subscribers.read()
+        {
+            for f in s.iter() {
+                f(old, new, changes);
+            }
+        }
+    }
+}
+
+/// A config file deserialized into any type, reloaded in the background when the file changes
+pub struct HotConfig<T> {
+    path: PathBuf,
+    debounce: Duration,
+    current: Arc<RwLock<Arc<T>>>,
+    notifier: Arc<Notifier<T>>,
+    sender: Arc<watch::Sender<Arc<T>>>,
+}
+
+impl<T> Clone for HotConfig<T> {
+    fn clone(&self) -> Self {
+        HotConfig {
+            path: self.path.clone(),
+            debounce: self.debounce,
+            current: self.current.clone(),
+            notifier: self.notifier.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> HotConfig<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// load the file, the format is chosen by its extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref().to_path_buf();
+        let value = Arc::new(read::<T>(&path)?);
+        Ok(HotConfig {
+            path,
+            debounce: DEBOUNCE,
+            current: Arc::new(RwLock::new(value.clone())),
+            notifier: Arc::new(Notifier::new()),
+            sender: Arc::new(watch::channel(value).0),
+        })
+    }
+
+    /// called with old and new values and the changed keys after a reload
+    pub fn subscribe<F>(&self, f: F)
+    where
+        F: Fn(&T, &T, &[Change]) + Send + Sync + 'static,
+    {
+        self.notifier.subscribe(f);
+    }
+
+    /// receives the new value after every reload that changed something
+    pub fn receiver(&self) -> watch::Receiver<Arc<T>> {
+        self.sender.subscribe()
+    }
+
+    /// reject a reload by returning an error, the current value is kept
+    pub fn validate<F>(&self, f: F)
+    where
+        F: Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.notifier.validate(f);
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the current value, cheap to clone and not affected by later reloads
+    pub fn snapshot(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(c) => c.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// read and validate the file again, the current value is kept on error
+    pub fn reload(&self) -> Result<Vec<Change>, ConfigError> {
+        let value = Arc::new(read::<T>(&self.path)?);
+        self.notifier.check(&value).map_err(ConfigError::Message)?;
+        // swap and publish in one critical section, so racing reloads publish in order
+        let (old, changes) = {
+            let mut current = match self.current.write() {
+                Ok(c) => c,
+                Err(e) => e.into_inner(),
+            };
+            let old = std::mem::replace(&mut *current, value.clone());
+            let changes = diff(&*old, &*value);
+            if !changes.is_empty() {
+                self.sender.send_replace(value.clone());
+            }
+            (old, changes)
+        };
+        self.notifier.notify(&old, &value, &changes);
+        Ok(changes)
+    }
+
+    /// quiet time after the last file event before reloading, `DEBOUNCE` by default
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// watch the file on a background thread until the guard is dropped, see `watcher`
+    pub fn watch(&self) -> Result<WatchGuard, notify::Error> {
+        let config = self.clone();
+        watcher(&self.path, self.debounce, move || config.on_change())
+    }
+
+    /// watch the file on a task of the current tokio runtime until the guard is dropped
+    pub fn watch_async(&self) -> Result<WatchGuard, notify::Error> {
+        let config = self.clone();
+        async_watcher(&self.path, self.debounce, move || config.on_change())
+    }
+
+    fn on_change(&self) {
+        match self.reload() {
+            Ok(_) => log::info!("reloaded configuration {:?}", self.path),
+            Err(e) => log::error!("reload {:?} error: {e:?}", self.path),
+        }
+    }
+}
+
+/// Stops watching when dropped, a reload waiting for the debounce is dropped too
+pub struct WatchGuard {
+    _watcher: RecommendedWatcher,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    deserialize(
+        Config::builder().add_source(File::from(path)).build()?,
+        None,
+    )
+}
+
+/// deserialize with `enc:v1:` values decrypted, see `decrypt_secrets`
+pub fn deserialize<'de, T: Deserialize<'de>>(
+    config: Config,
+    key: Option<&[u8]>,
+) -> Result<T, ConfigError> {
+    let mut value = config.cache;
+    decrypt_secrets(&mut value, key)?;
+    value.try_deserialize()
+}
+
+/// Like `deserialize`, also returning the keys that `T` does not know.
+pub fn deserialize_strict<'de, T: Deserialize<'de>>(
+    config: Config,
+    key: Option<&[u8]>,
+) -> Result<(T, Vec<UnknownKey>), ConfigError> {
+    let mut value = config.cache;
+    decrypt_secrets(&mut value, key)?;
+    let mut paths = vec![];
+    let t = serde_ignored::deserialize(value.clone(), |path| paths.push(path.to_string()))?;
+    let unknown = paths
+        .iter()
+        .map(|path| {
+            let keys = path
+                .split('.')
+                .filter(|k| *k != "?")
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            let source = find(&value, &keys).and_then(origin);
+            let line = source
+                .as_deref()
+                .and_then(|s| std::fs::read_to_string(s).ok())
+                .and_then(|text| find_line(&text, &keys));
+            UnknownKey {
+                key: keys.join("."),
+                source,
+                line,
+            }
+        })
+        .collect();
+    Ok((t, unknown))
+}
+
+/// A config key not used by the target type, probably a typo
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownKey {
+    pub key: String,
+    /// file path or `the environment`, `None` for embedded defaults
+    pub source: Option<String>,
+    /// 1-based line in `source`
+    pub line: Option<usize>,
+}
+
+impl std::fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown key `{}`", self.key)?;
+        match (&self.source, self.line) {
+            (Some(source), Some(line)) => write!(f, " in {source}:{line}"),
+            (Some(source), None) => write!(f, " in {source}"),
+            _ => write!(f, " in defaults"),
+        }
+    }
+}
+
+fn find<'a>(value: &'a config::Value, keys: &[String]) -> Option<&'a config::Value> {
+    keys.iter().try_fold(value, |v, k| match v.kind {
+        ValueKind::Table(ref t) => t.get(k),
+        ValueKind::Array(ref a) => a.get(k.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// origin of a value, for tables the origin of their first value
+fn origin(value: &config::Value) -> Option<String> {
+    value
+        .origin()
+        .map(str::to_string)
+        .or_else(|| match value.kind {
+            ValueKind::Table(ref t) => t.values().find_map(origin),
+            ValueKind::Array(ref a) => a.iter().find_map(origin),
+            _ => None,
+        })
+}
+
+/// line of the last key, looking for each key after the previous one,
+/// works for toml, yaml and json written one key per line
+fn find_line(text: &str, keys: &[String]) -> Option<usize> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut at = 0;
+    for key in keys.iter().filter(|k| k.parse::<usize>().is_err()) {
+        at += lines[at..].iter().position(|line| {
+            let line = line.trim_start().trim_start_matches(['[', '-', ' ']);
+            let end = line.find(['=', ':', ']', ' ']).unwrap_or(line.len());
+            line[..end]
+                .split('.')
+                .any(|k| k.trim_matches(['"', '\'']).eq_ignore_ascii_case(key))
+        })?;
+    }
+    Some(at + 1)
+}
+
+/// Replace `enc:v1:` strings with their plain text.
+/// Without `key` the master key is read from the environment, only when a secret is found.
+pub fn decrypt_secrets(value: &mut config::Value, key: Option<&[u8]>) -> Result<(), ConfigError> {
+    let mut key = key.map(<[u8]>::to_vec);
+    decrypt_value(value, &mut key)
+}
+
+fn decrypt_value(value: &mut config::Value, key: &mut Option<Vec<u8>>) -> Result<(), ConfigError> {
+    match value.kind {
+        ValueKind::String(ref mut s) if secret::is_secret(s) => {
+            if key.is_none() {
+                *key = Some(secret::master_key().map_err(|e| ConfigError::Message(e.to_string()))?);
+            }
+            let k = key.as_deref().unwrap_or_default();
+            *s = secret::decrypt_secret(k, s).map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+        ValueKind::Table(ref mut table) => {
+            for v in table.values_mut() {
+                decrypt_value(v, key)?;
+            }
+        }
+        ValueKind::Array(ref mut array) => {
+            for v in array.iter_mut() {
+                decrypt_value(v, key)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Server {
+        port: u16,
+    }
+
+    fn wait_port(config: &HotConfig<Server>, port: u16) -> bool {
+        for _ in 0..50 {
+            if config.snapshot().port == port {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn test_hot_config() {
+        let path = env::temp_dir().join("rskit-hot.toml");
+        std::fs::write(&path, "port = 8080\n").unwrap();
+
+        let config = HotConfig::<Server>::load(&path).unwrap();
+        let old = config.snapshot();
+        assert_eq!(8080, old.port);
+
+        let guard = config.watch().unwrap();
+        std::fs::write(&path, "port = 8081\n").unwrap();
+        assert!(wait_port(&config, 8081));
+        assert_eq!(8080, old.port);
+
+        // a bad edit keeps the current value, wait well past the debounce
+        std::fs::write(&path, "port = \"x\"\n").unwrap();
+        std::thread::sleep(DEBOUNCE * 4);
+        assert_eq!(8081, config.snapshot().port);
+
+        drop(guard);
+        std::fs::write(&path, "port = 8082\n").unwrap();
+        std::thread::sleep(DEBOUNCE * 3);
+        assert_eq!(8081, config.snapshot().port);
+        config.reload().unwrap();
+        assert_eq!(8082, config.snapshot().port);
+    }
+
+    #[test]
+    fn test_reload_race() {
+        let path = env::temp_dir().join("rskit-race.toml");
+        std::fs::write(&path, "port = 1\n").unwrap();
+        let config = HotConfig::<Server>::load(&path).unwrap();
+        // subscribers run without the lock held and may reload themselves
+        let c = config.clone();
+        config.subscribe(move |_, _, _| {
+            let _ = c.snapshot();
+            let _ = c.reload();
+        });
+        let threads = (0..8)
+            .map(|t| {
+                let (config, path) = (config.clone(), path.clone());
+                std::thread::spawn(move || {
+                    for i in 0..20 {
+                        let _ = std::fs::write(&path, format!("port = {}\n", t * 100 + i));
+                        let _ = config.reload();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // the last published value is the current one
+        assert_eq!(config.snapshot().port, config.receiver().borrow().port);
+    }
+
+    #[test]
+    fn test_drop_pending() {
+        let path = env::temp_dir().join("rskit-pending.toml");
+        std::fs::write(&path, "port = 8080\n").unwrap();
+        let config = HotConfig::<Server>::load(&path).unwrap();
+        let guard = config.watch().unwrap();
+        std::fs::write(&path, "port = 8081\n").unwrap();
+        std::thread::sleep(DEBOUNCE / 3);
+        drop(guard);
+        std::thread::sleep(DEBOUNCE * 3);
+        assert_eq!(8080, config.snapshot().port);
+    }
+
+    #[test]
+    fn test_debounce() {
+        let dir = env::temp_dir().join("rskit-debounce");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.toml");
+        std::fs::write(&path, "port = 8080\n").unwrap();
+
+        let config = HotConfig::<Server>::load(&path)
+            .unwrap()
+            .debounce(Duration::from_millis(200));
+        let reloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let r = reloads.clone();
+        config.subscribe(move |_, _, _| {
+            r.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        let _guard = config.watch().unwrap();
+
+        // several quick writes are one reload
+        for port in 8081..8086 {
+            std::fs::write(&path, format!("port = {port}\n")).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(wait_port(&config, 8085));
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(1, reloads.load(std::sync::atomic::Ordering::SeqCst));
+
+        // atomic save: write a temp file and rename it over the config
+        let tmp = dir.join("app.toml.tmp");
+        std::fs::write(&tmp, "port = 9090\n").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        assert!(wait_port(&config, 9090));
+        std::fs::write(&tmp, "port = 9091\n").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        assert!(wait_port(&config, 9091));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_swap() {
+        let dir = env::temp_dir().join("rskit-symlink");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("v1")).unwrap();
+        std::fs::create_dir_all(dir.join("v2")).unwrap();
+        std::fs::write(dir.join("v1/app.toml"), "port = 8080\n").unwrap();
+        std::fs::write(dir.join("v2/app.toml"), "port = 8081\n").unwrap();
+        // like a k8s configmap: app.toml -> ..data/app.toml, ..data -> v1
+        std::os::unix::fs::symlink("v1", dir.join("..data")).unwrap();
+        std::os::unix::fs::symlink("..data/app.toml", dir.join("app.toml")).unwrap();
+
+        let config = HotConfig::<Server>::load(dir.join("app.toml")).unwrap();
+        let _guard = config.watch().unwrap();
+        assert_eq!(8080, config.snapshot().port);
+
+        std::os::unix::fs::symlink("v2", dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        assert!(wait_port(&config, 8081));
+    }
+
+    #[tokio::test]
+    async fn test_watch_async() {
+        let path = env::temp_dir().join("rskit-async.toml");
+        std::fs::write(&path, "port = 8080\n").unwrap();
+
+        let config = HotConfig::<Server>::load(&path).unwrap();
+        let mut rx = config.receiver();
+        let guard = config.watch_async().unwrap();
+        std::fs::write(&path, "port = 8081\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(8081, rx.borrow().port);
+        drop(guard);
+
+        assert!(
+            std::thread::spawn(move || config.watch_async())
+                .join()
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        #[derive(Serialize)]
+        struct Db {
+            url: String,
+            pool: u32,
+            hosts: Vec<String>,
+        }
+        let old = Db {
+            url: "a".to_string(),
+            pool: 1,
+            hosts: vec!["h1".to_string()],
+        };
+        let new = Db {
+            url: "a".to_string(),
+            pool: 2,
+            hosts: vec!["h1".to_string(), "h2".to_string()],
+        };
+        let changes = diff(&old, &new);
+        assert_eq!(
+            vec!["hosts", "pool"],
+            changes.iter().map(|c| c.key.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(Value::from(1)), changes[1].old);
+        assert_eq!(Some(Value::from(2)), changes[1].new);
+        assert!(diff(&old, &old).is_empty());
+
+        let changes = diff(
+            &Settings::default(),
+            &Settings {
+                app: App {
+                    version: "0.0.2".to_string(),
+                },
+                log: None,
+            },
+        );
+        assert_eq!("app.version", changes[0].key);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        use std::sync::Mutex;
+        let path = env::temp_dir().join("rskit-subscribe.toml");
+        std::fs::write(&path, "port = 8080\n").unwrap();
+        let config = HotConfig::<Server>::load(&path).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let s = seen.clone();
+        config.subscribe(move |old, new, changes| {
+            s.lock()
+                .unwrap()
+                .push((old.port, new.port, changes[0].key.clone()));
+        });
+        config.validate(|s| match s.port {
+            0..1024 => Err(format!("port {} is reserved", s.port)),
+            _ => Ok(()),
+        });
+        let mut rx = config.receiver();
+
+        std::fs::write(&path, "port = 9090\n").unwrap();
+        assert_eq!("port", config.reload().unwrap()[0].key);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(9090, rx.borrow_and_update().port);
+
+        // unchanged file does not notify
+        assert!(config.reload().unwrap().is_empty());
+        assert!(!rx.has_changed().unwrap());
+
+        std::fs::write(&path, "port = 80\n").unwrap();
+        assert!(config.reload().is_err());
+        assert_eq!(9090, config.snapshot().port);
+
+        assert_eq!(
+            vec![(8080, 9090, "port".to_string())],
+            *seen.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        #[derive(Deserialize)]
+        struct Root {
+            server: Server,
+            name: Option<String>,
+        }
+
+        let dir = std::env::temp_dir().join("rskit-unknown");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.toml");
+        std::fs::write(
+            &path,
+            "name = \"app\"\nnmae = \"typo\"\n\n[server]\nport = 8080\nhots = \"localhost\"\n",
+        )
+        .unwrap();
+        let config = Config::builder()
+            .add_source(File::from(path.as_path()))
+            .build()
+            .unwrap();
+        let (root, unknown) = deserialize_strict::<Root>(config, None).unwrap();
+        assert_eq!(8080, root.server.port);
+        assert_eq!(Some("app".to_string()), root.name);
+        assert_eq!(2, unknown.len());
+        let hots = unknown.iter().find(|u| u.key == "server.hots").unwrap();
+        assert_eq!(Some(6), hots.line);
+        assert!(
+            hots.source
+                .as_ref()
+                .unwrap()
+                .ends_with("rskit-unknown/app.toml")
+        );
+        let nmae = unknown.iter().find(|u| u.key == "nmae").unwrap();
+        assert_eq!(Some(2), nmae.line);
+        assert!(
+            hots.to_string()
+                .starts_with("unknown key `server.hots` in ")
+        );
+        assert!(hots.to_string().ends_with("rskit-unknown/app.toml:6"));
+    }
+
+    #[test]
+    fn test_decrypt_secrets() {
+        use base64::{Engine, prelude::BASE64_STANDARD};
+        let key = BASE64_STANDARD.decode(secret::gen_master_key()).unwrap();
+        let toml = format!(
+            "[db]\nuser = \"bob\"\npassword = \"{}\"\nkeys = [\"{}\"]\n",
+            secret::encrypt_secret(&key, "123456").unwrap(),
+            secret::encrypt_secret(&key, "k1").unwrap(),
+        );
+        let config = || {
+            Config::builder()
+                .add_source(File::from_str(&toml, config::FileFormat::Toml))
+                .build()
+                .unwrap()
+        };
+
+        #[derive(Debug, Deserialize)]
+        struct Root {
+            db: Db,
+        }
+        #[derive(Debug, Deserialize)]
+        struct Db {
+            user: String,
+            password: String,
+            keys: Vec<String>,
+        }
+        let root = deserialize::<Root>(config(), Some(&key)).unwrap();
+        assert_eq!("bob", root.db.user);
+        assert_eq!("123456", root.db.password);
+        assert_eq!(vec!["k1".to_string()], root.db.keys);
+
+        let other = BASE64_STANDARD.decode(secret::gen_master_key()).unwrap();
+        assert!(deserialize::<Root>(config(), Some(&other)).is_err());
+    }
+
     #[test]
     fn test_auto_config() {
         init();
@@ -1,8 +1,10 @@
 use std::{sync::LazyLock, time::Duration};
 
-use reqwest::{Client, IntoUrl, Method, Response};
+use reqwest::{Client, IntoUrl, Method, Response, StatusCode};
 use serde::Serialize;
 
+use super::retry::Retry;
+
 pub static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(|| {
     Client::builder()
         .timeout(Duration::from_secs(30))
@@ -23,3 +25,138 @@ where
         }
     }
 }
+
+/// Like `request`, retrying with `retry` and returning the last response once
+/// attempts run out. By default connection errors are retried, and for idempotent
+/// methods also timeouts, 429 and 5xx responses, so a POST is not sent twice
+/// after a timeout. A `retry_on` for `RequestError` set on `retry` replaces that.
+pub async fn request_retry<U, D>(
+    method: Method,
+    url: U,
+    data: &D,
+    retry: &Retry,
+) -> Result<Response, ()>
+where
+    U: IntoUrl,
+    D: Serialize + ?Sized,
+{
+    let url = match url.into_url() {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!("request error: {:?}", e);
+            return Err(());
+        }
+    };
+    let retry = match retry.has_retry_on() {
+        true => retry.clone(),
+        false => {
+            let idempotent = is_idempotent(&method);
+            retry.clone().retry_on(move |e: &RequestError| match e {
+                RequestError::Send(e) => e.is_connect() || (idempotent && e.is_timeout()),
+                RequestError::Status(_) => idempotent,
+            })
+        }
+    };
+    let res = retry
+        .run(|| async {
+            let req = HTTP_CLIENT.request(method.clone(), url.clone()).json(data);
+            match req.send().await {
+                Ok(res) if retryable(res.status()) => Err(RequestError::Status(res)),
+                Ok(res) => Ok(res),
+                Err(e) => Err(RequestError::Send(e)),
+            }
+        })
+        .await;
+    match res {
+        Ok(res) | Err(RequestError::Status(res)) => Ok(res),
+        Err(RequestError::Send(e)) => {
+            log::error!("request error: {:?}", e);
+            Err(())
+        }
+    }
+}
+
+/// A failed attempt of `request_retry`, for `Retry::retry_on`
+#[derive(Debug)]
+pub enum RequestError {
+    Send(reqwest::Error),
+    /// a 429 or 5xx response
+    Status(Response),
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    [
+        Method::GET,
+        Method::HEAD,
+        Method::PUT,
+        Method::DELETE,
+        Method::OPTIONS,
+        Method::TRACE,
+    ]
+    .contains(method)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    /// answers every request with 503 and counts them
+    fn unavailable() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let n = count.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut len = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        len = v.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                reader.read_exact(&mut vec![0; len]).unwrap();
+                n.fetch_add(1, Ordering::SeqCst);
+                let res = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                let _ = stream.write_all(res.as_bytes());
+            }
+        });
+        (url, count)
+    }
+
+    #[tokio::test]
+    async fn test_request_retry() {
+        let retry = Retry::new()
+            .attempts(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let (url, count) = unavailable();
+        let res = request_retry(Method::GET, &url, &(), &retry).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        assert_eq!(3, count.load(Ordering::SeqCst));
+
+        // not idempotent, sent once
+        let (url, count) = unavailable();
+        let _ = request_retry(Method::POST, &url, &(), &retry).await;
+        assert_eq!(1, count.load(Ordering::SeqCst));
+
+        // unless asked for
+        let retry = retry.retry_on(|e: &RequestError| matches!(e, RequestError::Status(_)));
+        let (url, count) = unavailable();
+        let _ = request_retry(Method::POST, &url, &(), &retry).await;
+        assert_eq!(3, count.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,1121 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::BufWriter,
+    ops::Deref,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Local, Timelike};
+use fast_log::{
+    Logger,
+    appender::{Command, FastLogRecord, LogAppender},
+    consts::LogSize,
+    error::LogError,
+    filter::Filter,
+    plugin::file_split::{CanRollingPack, Packer},
+};
+use log::{
+    Level, LevelFilter, Record,
+    kv::{self, Key, Value, VisitSource},
+};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value as Json};
+
+/// Level directives in `RUST_LOG` style, e.g. `rskit::base::db=debug,hyper=warn,info`.
+/// A directive matches a record target equal to the module or below it,
+/// the longest match wins, otherwise the default level applies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directives {
+    pub level: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    pub fn new(level: LevelFilter) -> Self {
+        Directives {
+            level,
+            modules: vec![],
+        }
+    }
+
+    /// parse directives, a bare level sets the default,
+    /// `default` is used when the string has none
+    pub fn parse(s: &str, default: LevelFilter) -> Result<Self, String> {
+        let mut directives = Directives::new(default);
+        for item in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match item.split_once('=') {
+                Some((module, level)) => {
+                    let level = LevelFilter::from_str(level.trim())
+                        .map_err(|_| format!("invalid log level: {item}"))?;
+                    directives.modules.push((module.trim().to_string(), level));
+                }
+                None => match LevelFilter::from_str(item) {
+                    Ok(level) => directives.level = level,
+                    // a bare module name enables everything for it
+                    Err(_) => directives
+                        .modules
+                        .push((item.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        directives
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(directives)
+    }
+
+    pub fn level_of(&self, target: &str) -> LevelFilter {
+        for (module, level) in &self.modules {
+            if target == module
+                || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+            {
+                return *level;
+            }
+        }
+        self.level
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level_of(target)
+    }
+
+    /// the most verbose level of all directives, used as the global max level
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.level, Ord::max)
+    }
+}
+
+impl FromStr for Directives {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Directives::parse(s, LevelFilter::Error)
+    }
+}
+
+/// directives used by the installed logger, there is only one per process
+pub static LOG_DIRECTIVES: LazyLock<RwLock<Directives>> =
+    LazyLock::new(|| RwLock::new(Directives::new(LevelFilter::Trace)));
+
+/// replace the active directives, takes effect immediately
+pub fn set_directives(directives: Directives) {
+    log::set_max_level(directives.max_level());
+    if let Ok(mut d) = LOG_DIRECTIVES.write() {
+        *d = directives;
+    }
+}
+
+/// Returned by `Log::init*`, changes levels at runtime
+#[derive(Clone, Copy)]
+pub struct LogHandle {
+    logger: &'static Logger,
+}
+
+impl LogHandle {
+    pub(crate) fn new(logger: &'static Logger) -> Self {
+        LogHandle { logger }
+    }
+
+    pub fn logger(&self) -> &'static Logger {
+        self.logger
+    }
+
+    pub fn directives(&self) -> Directives {
+        match LOG_DIRECTIVES.read() {
+            Ok(d) => d.clone(),
+            Err(_) => Directives::new(log::max_level()),
+        }
+    }
+
+    /// set the default level, module directives are kept
+    pub fn set_level(&self, level: LevelFilter) {
+        let mut directives = self.directives();
+        directives.level = level;
+        set_directives(directives);
+    }
+
+    pub fn set_directives(&self, s: &str) -> Result<(), String> {
+        set_directives(Directives::parse(s, self.directives().level)?);
+        Ok(())
+    }
+}
+
+impl Deref for LogHandle {
+    type Target = Logger;
+
+    fn deref(&self) -> &Self::Target {
+        self.logger
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hourly,
+    Daily,
+}
+
+/// Roll the log file by size, by period or by whichever comes first
+pub struct LogRolling {
+    pub size: Option<LogSize>,
+    pub period: Option<Period>,
+    last: SystemTime,
+}
+
+impl LogRolling {
+    pub fn size(size: LogSize) -> Self {
+        LogRolling {
+            size: Some(size),
+            period: None,
+            last: SystemTime::now(),
+        }
+    }
+
+    pub fn daily() -> Self {
+        LogRolling::period(Period::Daily)
+    }
+
+    pub fn hourly() -> Self {
+        LogRolling::period(Period::Hourly)
+    }
+
+    pub fn period(period: Period) -> Self {
+        LogRolling {
+            size: None,
+            period: Some(period),
+            last: SystemTime::now(),
+        }
+    }
+
+    /// also roll when the file reaches `size`
+    pub fn max_size(mut self, size: LogSize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    fn changed(&self, last: SystemTime, now: SystemTime) -> bool {
+        let last = DateTime::<Local>::from(last);
+        let now = DateTime::<Local>::from(now);
+        match self.period {
+            Some(Period::Daily) => last.date_naive() != now.date_naive(),
+            Some(Period::Hourly) => {
+                last.date_naive() != now.date_naive() || last.hour() != now.hour()
+            }
+            None => false,
+        }
+    }
+}
+
+impl CanRollingPack for LogRolling {
+    fn can(
+        &mut self,
+        _packer: &dyn Packer,
+        temp_name: &str,
+        temp_size: usize,
+        arg: &FastLogRecord,
+    ) -> Option<String> {
+        let last = self.last;
+        self.last = arg.now;
+        let by_size = self.size.is_some_and(|s| temp_size >= s.len());
+        if !by_size && !self.changed(last, arg.now) {
+            return None;
+        }
+        // same naming as fast_log so its keep policies find the rolled files
+        let time = DateTime::<Local>::from(last)
+            .format("%Y-%m-%dT%H-%M-%S%.6f")
+            .to_string();
+        match temp_name.rfind('.') {
+            Some(idx) => Some(format!(
+                "{}{}{}",
+                &temp_name[..idx],
+                time,
+                &temp_name[idx..]
+            )),
+            None => Some(format!("{temp_name}{time}")),
+        }
+    }
+}
+
+/// Compression of rolled log files, the uncompressed copy is removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compress {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Packer for Compress {
+    fn pack_name(&self) -> &'static str {
+        match self {
+            Compress::None => "log",
+            Compress::Gzip => "gz",
+            Compress::Zstd => "zst",
+        }
+    }
+
+    fn do_pack(&self, mut log_file: File, log_file_path: &str) -> Result<bool, LogError> {
+        if let Compress::None = self {
+            return Ok(false);
+        }
+        let path = format!("{}.{}", log_file_path, self.pack_name());
+        let file = BufWriter::new(File::create(&path)?);
+        match self {
+            Compress::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                std::io::copy(&mut log_file, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Compress::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, 0)?;
+                std::io::copy(&mut log_file, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Compress::None => (),
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// fast_log plain text
+    #[default]
+    Text,
+    /// one json object per line
+    Json,
+}
+
+/// Format a record as one json line:
+/// time, level, target, module, file, line, thread, message and the key/value fields
+#[derive(Debug, Clone, Default)]
+pub struct JsonFormat {
+    fields: BTreeMap<String, String>,
+}
+
+impl JsonFormat {
+    pub fn new() -> Self {
+        JsonFormat::default()
+    }
+
+    /// static field added to every record, e.g. service name
+    pub fn field(mut self, key: &str, value: &str) -> Self {
+        self.fields.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn format(&self, record: &Record) -> String {
+        self.format_with(record, None)
+    }
+
+    /// format with message and key/value fields passed through the redactor
+    pub fn format_with(&self, record: &Record, redactor: Option<&Redactor>) -> String {
+        self.format_at(record, redactor, SystemTime::now())
+    }
+
+    /// like `format_with` with the time the record was created
+    pub fn format_at(
+        &self,
+        record: &Record,
+        redactor: Option<&Redactor>,
+        time: SystemTime,
+    ) -> String {
+        let mut map = Map::new();
+        for (k, v) in &self.fields {
+            map.insert(k.clone(), Json::String(v.clone()));
+        }
+        let _ = record
+            .key_values()
+            .visit(&mut JsonVisitor(&mut map, redactor));
+        let message = match redactor {
+            Some(r) => r.redact(&record.args().to_string()),
+            None => record.args().to_string(),
+        };
+
+        let thread = std::thread::current();
+        let thread = match thread.name() {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", thread.id()),
+        };
+        map.insert(
+            "time".to_string(),
+            Json::String(
+                DateTime::<Local>::from(time)
+                    .format("%Y-%m-%dT%H:%M:%S%.3f%:z")
+                    .to_string(),
+            ),
+        );
+        map.insert(
+            "level".to_string(),
+            Json::String(record.level().to_string()),
+        );
+        map.insert(
+            "target".to_string(),
+            Json::String(record.target().to_string()),
+        );
+        map.insert(
+            "module".to_string(),
+            Json::String(record.module_path().unwrap_or_default().to_string()),
+        );
+        map.insert(
+            "file".to_string(),
+            Json::String(record.file().unwrap_or_default().replace('\\', "/")),
+        );
+        map.insert("line".to_string(), record.line().unwrap_or_default().into());
+        map.insert("thread".to_string(), Json::String(thread));
+        map.insert("message".to_string(), Json::String(message));
+
+        let mut s = Json::Object(map).to_string();
+        s.push('\n');
+        s
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Json>, Option<&'a Redactor>);
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let json = if let Some(b) = value.to_bool() {
+            Json::Bool(b)
+        } else if let Some(i) = value.to_i64() {
+            Json::from(i)
+        } else if let Some(u) = value.to_u64() {
+            Json::from(u)
+        } else if let Some(f) = value.to_f64() {
+            Json::from(f)
+        } else {
+            Json::String(value.to_string())
+        };
+        let json = match self.1 {
+            Some(r) if r.is_sensitive(key.as_str()) => Json::String(MASK.to_string()),
+            // numbers stay numeric unless a pattern masks them, e.g. a card number
+            Some(r) => match json {
+                Json::String(s) => Json::String(r.redact(&s)),
+                Json::Number(n) if r.redact(&n.to_string()) != n.to_string() => {
+                    Json::String(MASK.to_string())
+                }
+                json => json,
+            },
+            None => json,
+        };
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+pub const MASK: &str = "***";
+
+/// keys masked by `Redactor::default()`, see `Redactor::is_sensitive`
+pub const SENSITIVE_KEYS: [&str; 8] = [
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "private_key",
+    "pri_key",
+    "api_key",
+    "credential",
+];
+
+/// Masks values of sensitive keys (`password=..`, `"token":".."`, `secret: ".."`)
+/// and values matching patterns (card numbers, emails) in log messages
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    keys: Vec<Vec<String>>,
+    patterns: Vec<Regex>,
+    card: bool,
+}
+
+impl Default for Redactor {
+    /// sensitive keys, card numbers and emails
+    fn default() -> Self {
+        SENSITIVE_KEYS
+            .iter()
+            .fold(Redactor::new(), |r, k| r.key(k))
+            .cards()
+            .pattern(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}")
+            .expect("email pattern error")
+    }
+}
+
+impl Redactor {
+    /// an empty redactor, add keys and patterns to it
+    pub fn new() -> Self {
+        Redactor {
+            keys: vec![],
+            patterns: vec![],
+            card: false,
+        }
+    }
+
+    pub fn key(mut self, key: &str) -> Self {
+        let key = words(key);
+        if !key.is_empty() && !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// mask every match of the regex
+    pub fn pattern(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// mask 13 to 19 digit numbers passing the luhn check
+    pub fn cards(mut self) -> Self {
+        self.card = true;
+        self
+    }
+
+    /// Whether the words of `key`, split at `_`, `-`, `.` and camelCase, contain
+    /// the words of a sensitive key in a row, a word may end with a plural `s`.
+    /// `db_password`, `apiKey` and `X-Auth-Tokens` match, `tokenizer` doesn't match `token`.
+    pub fn is_sensitive(&self, key: &str) -> bool {
+        let key = words(key);
+        self.keys.iter().any(|k| {
+            key.windows(k.len()).any(|w| {
+                w.iter()
+                    .zip(k)
+                    .all(|(a, b)| a == b || a.strip_suffix('s') == Some(b.as_str()))
+            })
+        })
+    }
+
+    pub fn redact(&self, s: &str) -> String {
+        let mut s = if self.keys.is_empty() {
+            s.to_string()
+        } else {
+            KEY_VALUE
+                .replace_all(s, |c: &Captures| {
+                    if self.is_sensitive(&c[1]) {
+                        format!("{}{}{}", &c[1], &c[2], MASK)
+                    } else {
+                        c[0].to_string()
+                    }
+                })
+                .to_string()
+        };
+        if self.card {
+            s = CARD
+                .replace_all(&s, |c: &Captures| {
+                    if luhn(&c[0]) {
+                        MASK.to_string()
+                    } else {
+                        c[0].to_string()
+                    }
+                })
+                .to_string();
+        }
+        for re in &self.patterns {
+            s = re.replace_all(&s, MASK).to_string();
+        }
+        s
+    }
+}
+
+static KEY_VALUE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\b([\w.-]*\w)("?\s*[:=]\s*)("[^"]*"|'[^']*'|[^\s,;&)}\]]+)"#)
+        .expect("key value pattern error")
+});
+
+/// lowercase words of a key split at non-alphanumerics and camelCase, `APIKey` is `api`, `key`
+fn words(key: &str) -> Vec<String> {
+    let chars = key.chars().collect::<Vec<_>>();
+    let mut words = vec![];
+    let mut word = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if !prev.is_uppercase() || next_lower {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+static CARD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").expect("card pattern error"));
+
+fn luhn(s: &str) -> bool {
+    let digits = s.chars().filter_map(|c| c.to_digit(10)).collect::<Vec<_>>();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match i % 2 {
+            1 if d * 2 > 9 => d * 2 - 9,
+            1 => d * 2,
+            _ => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// A value printed as `***` by `Debug`, `Display` and `Serialize`,
+/// deserialized transparently so it can be used in config structs
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Redacted<T>(pub T);
+
+impl<T> Redacted<T> {
+    pub fn new(v: T) -> Self {
+        Redacted(v)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Redacted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Redacted<T> {
+    fn from(v: T) -> Self {
+        Redacted(v)
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl<T> Serialize for Redacted<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(MASK)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Redacted<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        T::deserialize(d).map(Redacted)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedRecord {
+    pub level: Level,
+    pub target: String,
+    pub module_path: String,
+    pub file: String,
+    pub line: Option<u32>,
+    pub time: SystemTime,
+    pub message: String,
+}
+
+/// In-memory log buffer, see `Log::init_capture`.
+/// Every query flushes the logger first, so records logged before it are visible.
+#[derive(Clone, Default)]
+pub struct LogCapture {
+    records: Arc<Mutex<Vec<CapturedRecord>>>,
+}
+
+thread_local! {
+    /// captures receiving the records logged on this thread
+    static CAPTURES: RefCell<Vec<LogCapture>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps a capture active on the current thread, see `LogCapture::scoped`
+pub struct CaptureGuard {
+    capture: LogCapture,
+}
+
+impl Deref for CaptureGuard {
+    type Target = LogCapture;
+
+    fn deref(&self) -> &LogCapture {
+        &self.capture
+    }
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        let _ = CAPTURES.try_with(|c| {
+            c.borrow_mut()
+                .retain(|x| !Arc::ptr_eq(&x.records, &self.capture.records))
+        });
+    }
+}
+
+impl LogCapture {
+    pub fn new() -> Self {
+        LogCapture::default()
+    }
+
+    /// Receive the records `LogFilter` lets through on the current thread until
+    /// the guard drops, whichever `Log::init*` installed the logger.
+    /// Records of other threads, e.g. tokio workers, are not captured.
+    pub fn scoped(&self) -> CaptureGuard {
+        let _ = CAPTURES.try_with(|c| c.borrow_mut().push(self.clone()));
+        CaptureGuard {
+            capture: self.clone(),
+        }
+    }
+
+    fn push(&self, record: CapturedRecord) {
+        if let Ok(mut records) = self.records.lock() {
+            records.push(record);
+        }
+    }
+
+    pub fn records(&self) -> Vec<CapturedRecord> {
+        if let Ok(wg) = fast_log::flush() {
+            wg.wait();
+        }
+        match self.records.lock() {
+            Ok(records) => records.clone(),
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn filter<F>(&self, f: F) -> Vec<CapturedRecord>
+    where
+        F: Fn(&CapturedRecord) -> bool,
+    {
+        self.records().into_iter().filter(f).collect()
+    }
+
+    pub fn by_level(&self, level: Level) -> Vec<CapturedRecord> {
+        self.filter(|r| r.level == level)
+    }
+
+    /// records of the target module or below it
+    pub fn by_target(&self, target: &str) -> Vec<CapturedRecord> {
+        self.filter(|r| {
+            r.target == target
+                || (r.target.starts_with(target) && r.target[target.len()..].starts_with("::"))
+        })
+    }
+
+    pub fn containing(&self, s: &str) -> Vec<CapturedRecord> {
+        self.filter(|r| r.message.contains(s))
+    }
+
+    pub fn contains(&self, level: Level, s: &str) -> bool {
+        !self
+            .filter(|r| r.level == level && r.message.contains(s))
+            .is_empty()
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut records) = self.records.lock() {
+            records.clear();
+        }
+    }
+}
+
+impl LogAppender for LogCapture {
+    fn do_logs(&mut self, records: &[FastLogRecord]) {
+        if let Ok(mut captured) = self.records.lock() {
+            for x in records {
+                if let Command::CommandRecord = x.command {
+                    captured.push(CapturedRecord {
+                        level: x.level,
+                        target: x.target.clone(),
+                        module_path: x.module_path.clone(),
+                        file: x.file.clone(),
+                        line: x.line,
+                        time: x.now,
+                        message: x.args.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Runs on the logging thread before fast_log queues the record,
+/// so thread name and key/value fields are still available.
+/// Applies `LOG_DIRECTIVES`; records it formats itself are sent directly
+/// and dropped from the default path.
+pub struct LogFilter {
+    pub json: Option<JsonFormat>,
+    pub redactor: Option<Redactor>,
+}
+
+impl Filter for LogFilter {
+    fn do_log(&self, record: &Record) -> bool {
+        if let Ok(d) = LOG_DIRECTIVES.read()
+            && !d.enabled(record.target(), record.level())
+        {
+            return false;
+        }
+        capture(record, self.redactor.as_ref());
+        if self.json.is_none() && self.redactor.is_none() {
+            return true;
+        }
+        let mut r = to_record(record, String::new());
+        if let Some(ref redactor) = self.redactor {
+            r.args = redactor.redact(&r.args);
+        }
+        // text records are left unformatted, fast_log formats them with the redacted args
+        if let Some(ref json) = self.json {
+            r.formated = json.format_at(record, self.redactor.as_ref(), r.now);
+        }
+        if let Some(send) = fast_log::logger().send.get() {
+            let _ = send.send(r);
+        }
+        false
+    }
+}
+
+/// hands the record to the captures active on this thread
+fn capture(record: &Record, redactor: Option<&Redactor>) {
+    let _ = CAPTURES.try_with(|c| {
+        let captures = c.borrow();
+        if captures.is_empty() {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = match redactor {
+            Some(redactor) => redactor.redact(&message),
+            None => message,
+        };
+        for x in captures.iter() {
+            x.push(CapturedRecord {
+                level: record.level(),
+                target: record.target().to_string(),
+                module_path: record.module_path().unwrap_or_default().to_string(),
+                file: record.file().unwrap_or_default().to_string(),
+                line: record.line(),
+                time: SystemTime::now(),
+                message: message.clone(),
+            });
+        }
+    });
+}
+
+/// Drops every record, for a logger that only feeds captures
+pub(crate) struct Discard;
+
+impl LogAppender for Discard {
+    fn do_logs(&mut self, _records: &[FastLogRecord]) {}
+}
+
+pub(crate) fn to_record(record: &Record, formated: String) -> FastLogRecord {
+    FastLogRecord {
+        command: Command::CommandRecord,
+        level: record.level(),
+        target: record.target().to_string(),
+        args: record.args().to_string(),
+        module_path: record.module_path().unwrap_or_default().to_string(),
+        file: record.file().unwrap_or_default().to_string(),
+        line: record.line(),
+        now: SystemTime::now(),
+        formated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fast_log::{appender::LogAppender, plugin::file::FileAppender};
+
+    #[test]
+    fn test_directives() {
+        let d =
+            Directives::parse("rskit::base::db=debug,hyper=warn,info", LevelFilter::Error).unwrap();
+        assert_eq!(LevelFilter::Info, d.level);
+        assert_eq!(LevelFilter::Debug, d.max_level());
+        assert!(d.enabled("rskit::base::db", Level::Debug));
+        assert!(d.enabled("rskit::base::db::pool", Level::Debug));
+        assert!(!d.enabled("rskit::base::dbx", Level::Debug));
+        assert!(d.enabled("rskit::base::dbx", Level::Info));
+        assert!(!d.enabled("hyper::client", Level::Info));
+        assert!(d.enabled("hyper", Level::Warn));
+
+        let d = Directives::parse("rskit=trace,rskit::base=error", LevelFilter::Warn).unwrap();
+        assert_eq!(LevelFilter::Warn, d.level);
+        assert!(!d.enabled("rskit::base::conf", Level::Warn));
+        assert!(d.enabled("rskit::crypto", Level::Trace));
+
+        assert!(Directives::parse("rskit=loud", LevelFilter::Info).is_err());
+        assert_eq!(
+            Directives::new(LevelFilter::Debug),
+            "debug".parse::<Directives>().unwrap()
+        );
+    }
+
+    fn at(now: SystemTime) -> FastLogRecord {
+        let mut record = to_record(
+            &Record::builder().args(format_args!("")).build(),
+            String::new(),
+        );
+        record.now = now;
+        record
+    }
+
+    #[test]
+    fn test_rolling() {
+        use std::time::Duration;
+        let day = DateTime::<Local>::from(SystemTime::now())
+            .date_naive()
+            .and_hms_opt(10, 30, 0)
+            .unwrap()
+            .and_local_timezone(Local)
+            .unwrap();
+        let t0 = SystemTime::from(day);
+
+        let mut roll = LogRolling::daily().max_size(LogSize::KB(1));
+        roll.last = t0;
+        let packer = Compress::None;
+        let t1 = t0 + Duration::from_secs(3600);
+        assert_eq!(None, roll.can(&packer, "app.log", 10, &at(t1)));
+        let name = roll.can(&packer, "app.log", 1024, &at(t1)).unwrap();
+        assert!(name.starts_with("app") && name.ends_with(".log"));
+        let t2 = t0 + Duration::from_secs(24 * 3600);
+        assert!(roll.can(&packer, "app.log", 10, &at(t2)).is_some());
+
+        let mut roll = LogRolling::hourly();
+        roll.last = t0;
+        assert_eq!(
+            None,
+            roll.can(
+                &packer,
+                "app.log",
+                usize::MAX,
+                &at(t0 + Duration::from_secs(60))
+            )
+        );
+        assert!(roll.can(&packer, "app.log", 0, &at(t1)).is_some());
+    }
+
+    #[test]
+    fn test_compress() {
+        use std::io::Read;
+        let dir = std::env::temp_dir();
+        for compress in [Compress::Gzip, Compress::Zstd] {
+            let path = dir.join(format!("rskit-pack-{}.log", compress.pack_name()));
+            let path = path.to_str().unwrap();
+            std::fs::write(path, "hello log\n").unwrap();
+            assert!(compress.do_pack(File::open(path).unwrap(), path).unwrap());
+
+            let packed = File::open(format!("{}.{}", path, compress.pack_name())).unwrap();
+            let mut s = String::new();
+            match compress {
+                Compress::Gzip => flate2::read::GzDecoder::new(packed).read_to_string(&mut s),
+                _ => zstd::Decoder::new(packed).unwrap().read_to_string(&mut s),
+            }
+            .unwrap();
+            assert_eq!("hello log\n", s);
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        let r = Redactor::default();
+        assert_eq!(
+            "password=*** user=bob",
+            r.redact("password=123456 user=bob")
+        );
+        assert_eq!(
+            r#"{"db_password":***,"name":"ok"}"#,
+            r.redact(r#"{"db_password":"123456","name":"ok"}"#)
+        );
+        assert_eq!(
+            r#"App { token: ***, version: "0.1" }"#,
+            r.redact(r#"App { token: "abc", version: "0.1" }"#)
+        );
+        assert_eq!("Secret = ***", r.redact("Secret = 'x y'"));
+        assert_eq!("card *** paid", r.redact("card 4111 1111 1111 1111 paid"));
+        assert_eq!("order 1234567890123", r.redact("order 1234567890123"));
+        assert_eq!("mail *** now", r.redact("mail bob.li@example.com now"));
+        assert!(r.is_sensitive("API_KEY"));
+        assert!(r.is_sensitive("apiKey") && r.is_sensitive("APIKey"));
+        assert!(r.is_sensitive("X-Auth-Tokens") && r.is_sensitive("user.credentials"));
+        assert!(!r.is_sensitive("version"));
+        assert!(!r.is_sensitive("tokenizer") && !r.is_sensitive("secretary"));
+        assert!(!r.is_sensitive("key") && !r.is_sensitive("private"));
+        assert_eq!("tokenizer=bert", r.redact("tokenizer=bert"));
+        assert_eq!("access_token=*** n=1", r.redact("access_token=abc n=1"));
+
+        let r = Redactor::new().key("pin").pattern(r"\d{3}-\d{4}").unwrap();
+        assert_eq!("pin: *** call ***", r.redact("pin: 0000 call 555-1234"));
+        assert_eq!("password=1", r.redact("password=1"));
+        assert_eq!("shipping=2 pin_code=***", r.redact("shipping=2 pin_code=3"));
+    }
+
+    #[test]
+    fn test_redacted() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Db {
+            user: String,
+            password: Redacted<String>,
+        }
+        let db = serde_json::from_str::<Db>(r#"{"user":"bob","password":"123456"}"#).unwrap();
+        assert_eq!("123456", db.password.as_str());
+        assert_eq!(r#"Db { user: "bob", password: *** }"#, format!("{:?}", db));
+        assert_eq!("***", db.password.to_string());
+        assert_eq!(
+            r#"{"user":"bob","password":"***"}"#,
+            serde_json::to_string(&db).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json_redact() {
+        let json = JsonFormat::new();
+        let line = json.format_with(
+            &Record::builder()
+                .args(format_args!("login token={} from a@b.io", "abc"))
+                .level(Level::Info)
+                .key_values(&[("api_key", "k1"), ("user", "bob")])
+                .build(),
+            Some(&Redactor::default()),
+        );
+        let line = serde_json::from_str::<Json>(&line).unwrap();
+        assert_eq!("login token=*** from ***", line["message"]);
+        assert_eq!("***", line["api_key"]);
+        assert_eq!("bob", line["user"]);
+
+        let line = json.format_with(
+            &Record::builder()
+                .args(format_args!("paid"))
+                .level(Level::Info)
+                .key_values(&[
+                    ("order", Value::from(u64::MAX)),
+                    ("amount", Value::from(9.5)),
+                    ("card", Value::from(4111111111111111u64)),
+                    ("tokenizer", Value::from("bert")),
+                ])
+                .build(),
+            Some(&Redactor::default()),
+        );
+        let line = serde_json::from_str::<Json>(&line).unwrap();
+        assert_eq!(u64::MAX, line["order"]);
+        assert_eq!(9.5, line["amount"]);
+        assert_eq!("***", line["card"]);
+        assert_eq!("bert", line["tokenizer"]);
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture = LogCapture::new();
+        let mut appender = capture.clone();
+        appender.do_logs(&[
+            to_record(
+                &Record::builder()
+                    .args(format_args!("request error: timeout"))
+                    .level(Level::Error)
+                    .target("rskit::base::http")
+                    .build(),
+                String::new(),
+            ),
+            to_record(
+                &Record::builder()
+                    .args(format_args!("refreshing configuration ..."))
+                    .level(Level::Info)
+                    .target("rskit::base::conf")
+                    .build(),
+                String::new(),
+            ),
+        ]);
+
+        assert_eq!(2, capture.records().len());
+        assert_eq!(1, capture.by_level(Level::Error).len());
+        assert_eq!(1, capture.by_target("rskit::base::conf").len());
+        assert_eq!(2, capture.by_target("rskit::base").len());
+        assert_eq!(0, capture.by_target("rskit::ba").len());
+        assert_eq!("rskit::base::http", capture.containing("timeout")[0].target);
+        assert!(capture.contains(Level::Error, "request error"));
+        assert!(!capture.contains(Level::Warn, "request error"));
+
+        capture.clear();
+        assert!(capture.records().is_empty());
+    }
+
+    fn json_record(json: &JsonFormat, record: &Record) -> FastLogRecord {
+        to_record(record, json.format(record))
+    }
+
+    #[test]
+    fn test_json_format() {
+        let path = std::env::temp_dir().join("rskit-json.log");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let json = JsonFormat::new().field("service", "rskit");
+        let records = vec![
+            json_record(
+                &json,
+                &Record::builder()
+                    .args(format_args!("hello \"{}\"", "world"))
+                    .level(Level::Info)
+                    .target("rskit")
+                    .module_path(Some(module_path!()))
+                    .file(Some(file!()))
+                    .line(Some(line!()))
+                    .build(),
+            ),
+            json_record(
+                &json,
+                &Record::builder()
+                    .args(format_args!("login\nfailed"))
+                    .level(Level::Error)
+                    .target("rskit::user")
+                    .key_values(&[("user_id", 7)])
+                    .build(),
+            ),
+        ];
+        FileAppender::new(path).unwrap().do_logs(&records);
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let lines = content
+            .lines()
+            .map(|l| serde_json::from_str::<Json>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        assert_eq!("hello \"world\"", lines[0]["message"]);
+        assert_eq!("INFO", lines[0]["level"]);
+        assert_eq!("rskit", lines[0]["service"]);
+        assert_eq!(file!(), lines[0]["file"]);
+        assert_eq!("login\nfailed", lines[1]["message"]);
+        assert_eq!("rskit::user", lines[1]["target"]);
+        assert_eq!(7, lines[1]["user_id"]);
+
+        // the time is the one given, not when the line is written
+        let at = SystemTime::now() - std::time::Duration::from_secs(3600);
+        let line = json.format_at(&Record::builder().build(), None, at);
+        let line = serde_json::from_str::<Json>(&line).unwrap();
+        let time = DateTime::parse_from_rfc3339(line["time"].as_str().unwrap()).unwrap();
+        let at = DateTime::<Local>::from(at);
+        assert_eq!(at.timestamp_millis(), time.timestamp_millis());
+    }
+}
@@ -3,8 +3,11 @@ pub mod code;
 pub mod conf;
 pub mod db;
 pub mod http;
+pub mod logs;
 pub mod nosql;
 pub mod num;
+pub mod retry;
 pub mod sd;
 pub mod sys;
+pub mod tiered;
 pub mod timer;
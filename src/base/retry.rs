@@ -0,0 +1,282 @@
+use std::{
+    any::Any,
+    fmt::{self, Debug},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::time::Instant;
+
+/// Randomizes backoff delays so failing clients don't retry in lockstep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Jitter {
+    /// exactly the backoff delay
+    None,
+    /// anywhere between zero and the delay
+    #[default]
+    Full,
+    /// half the delay plus up to the other half
+    Equal,
+}
+
+type Predicate = Arc<dyn Fn(&dyn Any) -> bool + Send + Sync>;
+
+/// Retry policy with exponential backoff, see `Retry::run`
+#[derive(Clone)]
+pub struct Retry {
+    attempts: u32,
+    base: Duration,
+    max: Duration,
+    jitter: Jitter,
+    deadline: Option<Duration>,
+    retry_on: Option<Predicate>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            jitter: Jitter::Full,
+            deadline: None,
+            retry_on: None,
+        }
+    }
+}
+
+impl Debug for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Retry")
+            .field("attempts", &self.attempts)
+            .field("base", &self.base)
+            .field("max", &self.max)
+            .field("jitter", &self.jitter)
+            .field("deadline", &self.deadline)
+            .field("retry_on", &self.retry_on.is_some())
+            .finish()
+    }
+}
+
+impl Retry {
+    pub fn new() -> Self {
+        Retry::default()
+    }
+
+    /// total attempts including the first, at least 1
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// delays start at `base` and double after each attempt, up to `max`
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base = base;
+        self.max = max.max(base);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// no retry starts once `deadline` has passed since the first attempt,
+    /// a running attempt is not interrupted
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// only retry errors matching `f`, errors of another type than `E` are not
+    /// retried and logged as a warning, as the predicate was likely meant for them
+    pub fn retry_on<E, F>(mut self, f: F) -> Self
+    where
+        E: 'static,
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.retry_on = Some(Arc::new(move |e| match e.downcast_ref::<E>() {
+            Some(e) => f(e),
+            None => {
+                log::warn!(
+                    "retry_on expects {}, the error is not retried",
+                    std::any::type_name::<E>()
+                );
+                false
+            }
+        }));
+        self
+    }
+
+    /// whether `retry_on` was set
+    pub(crate) fn has_retry_on(&self) -> bool {
+        self.retry_on.is_some()
+    }
+
+    /// the delay before retrying after `attempt` failed, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base.saturating_mul(factor).min(self.max);
+        let ms = delay.as_millis() as u64;
+        match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => Duration::from_millis(rand::thread_rng().gen_range(0..=ms)),
+            Jitter::Equal => {
+                Duration::from_millis(ms / 2 + rand::thread_rng().gen_range(0..=ms - ms / 2))
+            }
+        }
+    }
+
+    fn retryable(&self, e: &dyn Any) -> bool {
+        self.retry_on.as_ref().is_none_or(|f| f(e))
+    }
+
+    /// calls `f` until it succeeds, the error isn't retryable, attempts run out
+    /// or the deadline passes, then returns the last result
+    pub async fn run<F, Fut, T, E>(&self, mut f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Debug + 'static,
+    {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let e = match f().await {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            if attempt >= self.attempts || !self.retryable(&e) {
+                return Err(e);
+            }
+            let delay = self.delay(attempt);
+            if let Some(deadline) = self.deadline
+                && start.elapsed() + delay > deadline
+            {
+                log::warn!("attempt {attempt} failed: {e:?}, deadline reached");
+                return Err(e);
+            }
+            log::warn!(
+                "attempt {attempt}/{} failed: {e:?}, retry in {delay:?}",
+                self.attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// shortcut for `Retry::run` with the default policy
+pub async fn retry<F, Fut, T, E>(f: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Debug + 'static,
+{
+    Retry::default().run(f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = Retry::new()
+            .backoff(Duration::from_millis(100), Duration::from_millis(500))
+            .jitter(Jitter::None);
+        let delays: Vec<_> = (1..=5).map(|n| policy.delay(n).as_millis()).collect();
+        assert_eq!(vec![100, 200, 400, 500, 500], delays);
+        assert_eq!(Duration::from_millis(500), policy.delay(u32::MAX));
+
+        let full = policy.clone().jitter(Jitter::Full);
+        let equal = policy.jitter(Jitter::Equal);
+        for _ in 0..100 {
+            assert!(full.delay(3) <= Duration::from_millis(400));
+            let d = equal.delay(3);
+            assert!(d >= Duration::from_millis(200) && d <= Duration::from_millis(400));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = Retry::new()
+            .attempts(4)
+            .backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let n = AtomicU32::new(0);
+        let res = policy
+            .run(|| async {
+                match n.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err("busy"),
+                    i => Ok(i),
+                }
+            })
+            .await;
+        assert_eq!(Ok(2), res);
+
+        n.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(|| async {
+                n.fetch_add(1, Ordering::SeqCst);
+                Err("down")
+            })
+            .await;
+        assert_eq!(Err("down"), res);
+        assert_eq!(4, n.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_retry_on() {
+        let policy = Retry::new()
+            .attempts(5)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .retry_on(|e: &u16| *e >= 500);
+        let n = AtomicU32::new(0);
+        let res: Result<(), u16> = policy
+            .run(|| async {
+                match n.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(503),
+                    _ => Err(404),
+                }
+            })
+            .await;
+        assert_eq!(Err(404), res);
+        assert_eq!(2, n.load(Ordering::SeqCst));
+
+        // a predicate for another error type retries nothing
+        let policy = policy.retry_on(|e: &String| e == "busy");
+        n.store(0, Ordering::SeqCst);
+        let res: Result<(), &str> = policy
+            .run(|| async {
+                n.fetch_add(1, Ordering::SeqCst);
+                Err("busy")
+            })
+            .await;
+        assert_eq!(Err("busy"), res);
+        assert_eq!(1, n.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let policy = Retry::new()
+            .attempts(100)
+            .backoff(Duration::from_millis(20), Duration::from_millis(20))
+            .jitter(Jitter::None)
+            .deadline(Duration::from_millis(100));
+        let n = AtomicU32::new(0);
+        let start = Instant::now();
+        let res: Result<(), _> = policy
+            .run(|| async {
+                n.fetch_add(1, Ordering::SeqCst);
+                Err(())
+            })
+            .await;
+        assert!(res.is_err());
+        assert!((4..=6).contains(&n.load(Ordering::SeqCst)));
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,584 @@
+use std::{
+    any::Any,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use redis::{Client, Commands};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{cache::Cache, sd};
+
+/// How values are stored in redis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// bincode, see `sd::to_bin`
+    #[default]
+    Bin,
+    /// readable from other languages, see `sd::to_json`
+    Json,
+}
+
+impl Codec {
+    fn encode<V: Serialize>(&self, v: &V) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Bin => sd::to_bin(v),
+            Codec::Json => sd::to_json(v).map(String::into_bytes),
+        }
+    }
+
+    fn decode<V: DeserializeOwned>(&self, b: &[u8]) -> Result<V, String> {
+        match self {
+            Codec::Bin => sd::from_bin(b),
+            Codec::Json => match std::str::from_utf8(b) {
+                Ok(s) => sd::from_json(s),
+                Err(e) => Err(format!("can not deserialize json: {:?}", e)),
+            },
+        }
+    }
+}
+
+/// A local `Cache` in front of redis, shared by all instances.
+/// Writes go to both and are broadcast over redis pub/sub so the other
+/// instances drop their local copy, see `listen`.
+/// Local copies expire with the redis key, or earlier with the ttl of the local tier.
+pub struct TieredCache {
+    local: Arc<Cache>,
+    generations: Arc<Generations>,
+    client: Client,
+    prefix: String,
+    codec: Codec,
+    ttl: Option<Duration>,
+    id: String,
+}
+
+impl TieredCache {
+    pub fn new(client: Client) -> Self {
+        TieredCache {
+            local: Arc::new(Cache::new()),
+            generations: Arc::new(Generations::new()),
+            client,
+            prefix: "cache:".to_string(),
+            codec: Codec::default(),
+            ttl: None,
+            id: hex::encode(rand::random::<[u8; 8]>()),
+        }
+    }
+
+    /// the local tier, e.g. `Cache::new().ttl(..).capacity(..)`
+    pub fn local(mut self, cache: Cache) -> Self {
+        self.local = Arc::new(cache);
+        self
+    }
+
+    /// prefix of redis keys and of the invalidation channel, `cache:` by default
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// expiry of redis keys, they never expire without it
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn cache(&self) -> &Arc<Cache> {
+        &self.local
+    }
+
+    /// channel carrying `<instance id> <key>` for every write
+    pub fn channel(&self) -> String {
+        format!("{}invalidate", self.prefix)
+    }
+
+    fn key(&self, k: &str) -> String {
+        format!("{}{}", self.prefix, k)
+    }
+
+    pub fn set<V>(&self, k: &str, v: V) -> Result<(), String>
+    where
+        V: Serialize + Any + Send + Sync + Clone,
+    {
+        let bytes = self.codec.encode(&v)?;
+        let mut conn = self.client.get_connection().map_err(|e| e.to_string())?;
+        let result: redis::RedisResult<()> = match self.ttl {
+            Some(ttl) => conn.pset_ex(self.key(k), bytes, ttl.as_millis() as u64),
+            None => conn.set(self.key(k), bytes),
+        };
+        result.map_err(|e| e.to_string())?;
+        self.generations.invalidate(&self.local, k);
+        self.keep(k, v, self.ttl);
+        self.publish(&mut conn, k)
+    }
+
+    /// local value, else the redis value which is then kept locally
+    pub fn get<V>(&self, k: &str) -> Result<Option<V>, String>
+    where
+        V: DeserializeOwned + Any + Send + Sync + Clone,
+    {
+        if let Some(v) = self.local.get::<V>(k) {
+            return Ok(Some(v));
+        }
+        // an invalidation arriving while redis is read must win over what was read
+        let generation = self.generations.get(k);
+        let mut conn = self.client.get_connection().map_err(|e| e.to_string())?;
+        let (bytes, pttl): (Option<Vec<u8>>, i64) = redis::pipe()
+            .get(self.key(k))
+            .pttl(self.key(k))
+            .query(&mut conn)
+            .map_err(|e| e.to_string())?;
+        match bytes {
+            Some(b) => {
+                let v: V = self.codec.decode(&b)?;
+                // -1 means no expiry
+                let ttl = u64::try_from(pttl).ok().map(Duration::from_millis);
+                self.fill(k, v.clone(), ttl, generation);
+                Ok(Some(v))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// keep a value read from redis unless its key was invalidated since `generation`
+    fn fill<V>(&self, k: &str, v: V, ttl: Option<Duration>, generation: u64)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        self.keep(k, v, ttl);
+        if self.generations.get(k) != generation {
+            self.local.remove(k);
+        }
+    }
+
+    /// local copy expiring with redis, capped by the ttl of the local tier
+    fn keep<V>(&self, k: &str, v: V, ttl: Option<Duration>)
+    where
+        V: Any + Send + Sync + Clone,
+    {
+        match (ttl, self.local.default_ttl()) {
+            (Some(ttl), Some(max)) => self.local.set_with_ttl(k, v, ttl.min(max)),
+            (Some(ttl), None) => self.local.set_with_ttl(k, v, ttl),
+            (None, _) => self.local.set(k, v),
+        }
+    }
+
+    pub fn remove(&self, k: &str) -> Result<(), String> {
+        let mut conn = self.client.get_connection().map_err(|e| e.to_string())?;
+        conn.del::<_, ()>(self.key(k)).map_err(|e| e.to_string())?;
+        self.local.remove(k);
+        self.publish(&mut conn, k)
+    }
+
+    fn publish(&self, conn: &mut redis::Connection, k: &str) -> Result<(), String> {
+        conn.publish::<_, _, ()>(self.channel(), format!("{} {}", self.id, k))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Drop local copies of keys written by other instances, on a thread
+    /// subscribed to `channel` until the returned guard is dropped.
+    /// A lost subscription is retried with backoff and the local tier is
+    /// cleared once it is back, as invalidations may have been missed.
+    pub fn listen(&self) -> Result<Listener, String> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (client, local, id) = (self.client.clone(), self.local.clone(), self.id.clone());
+        let generations = self.generations.clone();
+        let (channel, stopped) = (self.channel(), stop.clone());
+        std::thread::spawn(move || {
+            let mut first = Some(tx);
+            let mut delay = RECONNECT_MIN;
+            while !stopped.load(Ordering::Relaxed) {
+                let mut conn = match connect(&client) {
+                    Ok(conn) => conn,
+                    Err(e) => match first.take() {
+                        Some(tx) => {
+                            let _ = tx.send(Err(e.to_string()));
+                            return;
+                        }
+                        None => {
+                            log::error!("invalidation channel {channel} error: {e:?}");
+                            delay = backoff(delay, &stopped);
+                            continue;
+                        }
+                    },
+                };
+                let mut pubsub = conn.as_pubsub();
+                match (pubsub.subscribe(&channel), first.take()) {
+                    (Ok(_), Some(tx)) => {
+                        let _ = tx.send(Ok(()));
+                    }
+                    (Ok(_), None) => {
+                        log::info!("invalidation channel {channel} resubscribed");
+                        generations.invalidate_all(&local);
+                        delay = RECONNECT_MIN;
+                    }
+                    (Err(e), Some(tx)) => {
+                        let _ = tx.send(Err(e.to_string()));
+                        return;
+                    }
+                    (Err(e), None) => {
+                        log::error!("invalidation channel {channel} error: {e:?}");
+                        delay = backoff(delay, &stopped);
+                        continue;
+                    }
+                }
+                while !stopped.load(Ordering::Relaxed) {
+                    match pubsub.get_message() {
+                        Ok(msg) => match msg.get_payload::<String>() {
+                            Ok(payload) => {
+                                if let Some((from, k)) = payload.split_once(' ')
+                                    && from != id
+                                {
+                                    generations.invalidate(&local, k);
+                                }
+                            }
+                            Err(e) => log::error!("invalidation payload error: {e:?}"),
+                        },
+                        Err(e) if e.is_timeout() => (),
+                        Err(e) => {
+                            log::error!("invalidation channel {channel} error: {e:?}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        match rx.recv() {
+            Ok(Ok(_)) => Ok(Listener { stop }),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+const STRIPES: usize = 64;
+
+/// Invalidation counters of keys hashed into stripes, so a read of redis can
+/// tell whether an invalidation of its key arrived meanwhile
+struct Generations(Vec<AtomicU64>);
+
+impl Generations {
+    fn new() -> Self {
+        Generations((0..STRIPES).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    fn stripe(&self, k: &str) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        &self.0[hasher.finish() as usize % STRIPES]
+    }
+
+    fn get(&self, k: &str) -> u64 {
+        self.stripe(k).load(Ordering::SeqCst)
+    }
+
+    /// bump before removing, a reader inserting after the removal sees the bump
+    fn invalidate(&self, local: &Cache, k: &str) {
+        self.stripe(k).fetch_add(1, Ordering::SeqCst);
+        local.remove(k);
+    }
+
+    fn invalidate_all(&self, local: &Cache) {
+        for g in &self.0 {
+            g.fetch_add(1, Ordering::SeqCst);
+        }
+        local.clear();
+    }
+}
+
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+fn connect(client: &Client) -> redis::RedisResult<redis::Connection> {
+    let conn = client.get_connection()?;
+    conn.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(conn)
+}
+
+/// sleep `delay` unless stopped meanwhile, returns the next delay
+fn backoff(delay: Duration, stopped: &AtomicBool) -> Duration {
+    let until = std::time::Instant::now() + delay;
+    while !stopped.load(Ordering::Relaxed) && std::time::Instant::now() < until {
+        std::thread::sleep(READ_TIMEOUT.min(until - std::time::Instant::now()));
+    }
+    (delay * 2).min(RECONNECT_MAX)
+}
+
+/// Stops listening for invalidations when dropped
+pub struct Listener {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+        time::Instant,
+    };
+
+    type Subscribers = Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>;
+    type Data = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>>>;
+
+    /// Just enough of a redis server for these tests:
+    /// GET, SET, PSETEX, PTTL, DEL, PUBLISH, SUBSCRIBE.
+    fn stand_in() -> (Client, Subscribers) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let data: Data = Arc::new(Mutex::new(HashMap::new()));
+        let subscribers: Subscribers = Arc::new(Mutex::new(HashMap::new()));
+        let subs = subscribers.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (data, subscribers) = (data.clone(), subscribers.clone());
+                std::thread::spawn(move || serve(stream, data, subscribers));
+            }
+        });
+        (Client::open(url).unwrap(), subs)
+    }
+
+    fn bulk(b: &[u8]) -> Vec<u8> {
+        [format!("${}\r\n", b.len()).as_bytes(), b, b"\r\n"].concat()
+    }
+
+    fn serve(stream: TcpStream, data: Data, subs: Subscribers) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line).is_ok_and(|n| n > 0) {
+            let n = line.trim()[1..].parse::<usize>().unwrap_or(0);
+            let mut args = vec![];
+            for _ in 0..n {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                let len = line.trim()[1..].parse::<usize>().unwrap();
+                let mut buf = vec![0; len + 2];
+                reader.read_exact(&mut buf).unwrap();
+                buf.truncate(len);
+                args.push(buf);
+            }
+            line.clear();
+            data.lock()
+                .unwrap()
+                .retain(|_, (_, at)| at.is_none_or(|at| at > Instant::now()));
+            let reply = match args[0].to_ascii_uppercase().as_slice() {
+                b"GET" => match data.lock().unwrap().get(&args[1]) {
+                    Some((v, _)) => bulk(v),
+                    None => b"$-1\r\n".to_vec(),
+                },
+                b"PTTL" => match data.lock().unwrap().get(&args[1]) {
+                    Some((_, Some(at))) => {
+                        format!(":{}\r\n", (*at - Instant::now()).as_millis()).into_bytes()
+                    }
+                    Some((_, None)) => b":-1\r\n".to_vec(),
+                    None => b":-2\r\n".to_vec(),
+                },
+                b"SET" => {
+                    let v = (args[2].clone(), None);
+                    data.lock().unwrap().insert(args[1].clone(), v);
+                    b"+OK\r\n".to_vec()
+                }
+                b"PSETEX" => {
+                    let ms = String::from_utf8_lossy(&args[2]).parse().unwrap();
+                    let at = Instant::now() + Duration::from_millis(ms);
+                    let v = (args[3].clone(), Some(at));
+                    data.lock().unwrap().insert(args[1].clone(), v);
+                    b"+OK\r\n".to_vec()
+                }
+                b"DEL" => {
+                    let n = args[1..]
+                        .iter()
+                        .filter(|k| data.lock().unwrap().remove(*k).is_some())
+                        .count();
+                    format!(":{n}\r\n").into_bytes()
+                }
+                b"PUBLISH" => {
+                    let msg = [
+                        b"*3\r\n".to_vec(),
+                        bulk(b"message"),
+                        bulk(&args[1]),
+                        bulk(&args[2]),
+                    ]
+                    .concat();
+                    let mut subs = subs.lock().unwrap();
+                    let streams = subs.entry(args[1].clone()).or_default();
+                    streams.retain_mut(|s| s.write_all(&msg).is_ok());
+                    format!(":{}\r\n", streams.len()).into_bytes()
+                }
+                b"SUBSCRIBE" => {
+                    let stream = writer.try_clone().unwrap();
+                    subs.lock()
+                        .unwrap()
+                        .entry(args[1].clone())
+                        .or_default()
+                        .push(stream);
+                    [
+                        b"*3\r\n".to_vec(),
+                        bulk(b"subscribe"),
+                        bulk(&args[1]),
+                        b":1\r\n".to_vec(),
+                    ]
+                    .concat()
+                }
+                b"UNSUBSCRIBE" | b"PUNSUBSCRIBE" => {
+                    let kind = args[0].to_ascii_lowercase();
+                    [b"*3\r\n".to_vec(), bulk(&kind), b"$-1\r\n:0\r\n".to_vec()].concat()
+                }
+                _ => b"+OK\r\n".to_vec(),
+            };
+            if writer.write_all(&reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn wait<F: Fn() -> bool>(f: F) -> bool {
+        for _ in 0..50 {
+            if f() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_tiered() {
+        let (client, _) = stand_in();
+        let a = TieredCache::new(client.clone());
+        let b = TieredCache::new(client);
+        let _la = a.listen().unwrap();
+        let _lb = b.listen().unwrap();
+
+        a.set("k", 1u32).unwrap();
+        assert_eq!(Some(1), b.get::<u32>("k").unwrap());
+        // the first read is not kept if the invalidation of the write was still in flight
+        assert!(wait(|| {
+            b.get::<u32>("k").unwrap();
+            b.cache().get::<u32>("k") == Some(1)
+        }));
+
+        // b drops its local copy once a writes
+        a.set("k", 2u32).unwrap();
+        assert!(wait(|| b.cache().get::<u32>("k").is_none()));
+        assert_eq!(Some(2), b.get::<u32>("k").unwrap());
+        assert_eq!(Some(2), a.cache().get::<u32>("k"));
+
+        b.remove("k").unwrap();
+        assert!(wait(|| a.cache().get::<u32>("k").is_none()));
+        assert_eq!(None, a.get::<u32>("k").unwrap());
+    }
+
+    #[test]
+    fn test_resubscribe() {
+        let (client, subscribers) = stand_in();
+        let a = TieredCache::new(client.clone());
+        let b = TieredCache::new(client);
+        let _lb = b.listen().unwrap();
+        a.set("k", 1u32).unwrap();
+        assert_eq!(Some(1), b.get::<u32>("k").unwrap());
+
+        // the subscription drops, like on a redis restart
+        for s in subscribers
+            .lock()
+            .unwrap()
+            .values_mut()
+            .flat_map(|s| s.drain(..))
+        {
+            s.shutdown(std::net::Shutdown::Both).unwrap();
+        }
+        // b may have missed invalidations meanwhile, so it starts over
+        assert!(wait(|| b.cache().get::<u32>("k").is_none()));
+        assert!(wait(|| !subscribers
+            .lock()
+            .unwrap()
+            .values()
+            .all(Vec::is_empty)));
+        assert_eq!(Some(1), b.get::<u32>("k").unwrap());
+        a.set("k", 2u32).unwrap();
+        assert!(wait(|| b.cache().get::<u32>("k").is_none()));
+    }
+
+    #[test]
+    fn test_codec() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct User {
+            name: String,
+            age: u8,
+        }
+        let user = User {
+            name: "alice".to_string(),
+            age: 30,
+        };
+        let (client, _) = stand_in();
+        for codec in [Codec::Bin, Codec::Json] {
+            let cache = TieredCache::new(client.clone())
+                .codec(codec)
+                .ttl(Duration::from_secs(60));
+            cache.set("user", user.clone()).unwrap();
+            let other = TieredCache::new(client.clone()).codec(codec);
+            assert_eq!(Some(user.clone()), other.get::<User>("user").unwrap());
+        }
+        let mut conn = client.get_connection().unwrap();
+        let json: String = conn.get("cache:user").unwrap();
+        assert_eq!(r#"{"name":"alice","age":30}"#, json);
+    }
+
+    #[test]
+    fn test_local_ttl() {
+        let (client, _) = stand_in();
+        let a = TieredCache::new(client.clone()).ttl(Duration::from_millis(300));
+        let b = TieredCache::new(client.clone());
+        a.set("k", 1u32).unwrap();
+        assert!(a.cache().expires_in("k").unwrap() <= Duration::from_millis(300));
+        assert_eq!(Some(1), b.get::<u32>("k").unwrap());
+        assert!(b.cache().expires_in("k").unwrap() <= Duration::from_millis(300));
+        // both tiers expire without any invalidation
+        std::thread::sleep(Duration::from_millis(350));
+        assert_eq!(None, b.cache().get::<u32>("k"));
+        assert_eq!(None, b.get::<u32>("k").unwrap());
+
+        // keys without expiry are capped by the local ttl
+        let c = TieredCache::new(client.clone()).local(Cache::new().ttl(Duration::from_secs(5)));
+        TieredCache::new(client).set("n", 2u32).unwrap();
+        assert_eq!(Some(2), c.get::<u32>("n").unwrap());
+        assert!(c.cache().expires_in("n").unwrap() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_invalidated_read() {
+        let (client, _) = stand_in();
+        let a = TieredCache::new(client.clone());
+        let b = TieredCache::new(client);
+        a.set("k", 1u32).unwrap();
+        // b has read 1 from redis when the invalidation of a's write arrives
+        let generation = b.generations.get("k");
+        b.generations.invalidate(&b.local, "k");
+        b.fill("k", 1u32, None, generation);
+        assert_eq!(None, b.cache().get::<u32>("k"));
+        // without one the value is kept
+        b.fill("k", 1u32, None, b.generations.get("k"));
+        assert_eq!(Some(1), b.cache().get::<u32>("k"));
+    }
+}
@@ -1,6 +1,23 @@
-use std::str::FromStr;
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use sqlx::SqlitePool;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::{Semaphore, watch},
+    task::{JoinHandle, JoinSet},
+};
+
+use super::retry::Retry;
+
+pub use chrono_tz::Tz;
 
 pub async fn task_cron<F>(cron: &str, f: F)
 where
@@ -9,10 +26,13 @@ where
 {
     if let Ok(schedule) = cron::Schedule::from_str(cron) {
         for interval in schedule.upcoming(Local) {
-            if let Ok(duration) = interval.signed_duration_since(Local::now()).to_std() {
-                tokio::time::sleep(duration).await;
-                f();
-            }
+            // a time already past runs now instead of being dropped
+            let duration = interval
+                .signed_duration_since(Local::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(duration).await;
+            f();
         }
     }
 }
@@ -23,14 +43,139 @@ where
 {
     if let Ok(schedule) = cron::Schedule::from_str(cron) {
         for interval in schedule.upcoming(Local) {
-            if let Ok(duration) = interval.signed_duration_since(Local::now()).to_std() {
+            let duration = interval
+                .signed_duration_since(Local::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(duration).await;
+            f(interval);
+        }
+    }
+}
+
+/// like `task_cron` with the times in `tz`, e.g. `"Asia/Shanghai".parse::<Tz>()`
+pub async fn task_cron_tz<F>(cron: &str, tz: Tz, f: F)
+where
+    F: Fn(),
+    F: Send + 'static,
+{
+    task_cron_interval_tz(cron, tz, move |_| f()).await
+}
+
+/// like `task_cron_interval` with the times in `tz`
+pub async fn task_cron_interval_tz<F>(cron: &str, tz: Tz, f: F)
+where
+    F: Fn(DateTime<Tz>),
+    F: Send + 'static,
+{
+    match CronSchedule::new(cron, tz) {
+        Ok(schedule) => {
+            for interval in schedule.upcoming() {
+                let duration = (interval.to_utc() - Utc::now())
+                    .to_std()
+                    .unwrap_or_default();
                 tokio::time::sleep(duration).await;
                 f(interval);
             }
         }
+        Err(e) => log::error!("cron {cron} error: {e:?}"),
     }
 }
 
+/// A cron schedule with its times in a timezone.
+///
+/// Around DST changes a time in the skipped hour runs once when the clocks
+/// have moved on, e.g. 02:30 at 03:30, and a time in the repeated hour runs
+/// once. Schedules running every hour follow real time instead: nothing is
+/// shifted into the skipped hour and the repeated hour runs twice.
+#[derive(Debug, Clone)]
+pub struct CronSchedule<Z: TimeZone = Local> {
+    schedule: cron::Schedule,
+    tz: Z,
+    every_hour: bool,
+}
+
+impl<Z: TimeZone> CronSchedule<Z> {
+    pub fn new(cron: &str, tz: Z) -> Result<Self, cron::error::Error> {
+        let schedule = cron::Schedule::from_str(cron)?;
+        let every_hour = cron::TimeUnitSpec::is_all(schedule.hours());
+        Ok(CronSchedule {
+            schedule,
+            tz,
+            every_hour,
+        })
+    }
+
+    pub fn timezone(&self) -> &Z {
+        &self.tz
+    }
+
+    /// the first time strictly after `t`
+    pub fn after(&self, t: &DateTime<Z>) -> Option<DateTime<Z>> {
+        let t = t.with_timezone(&self.tz);
+        let naive = t.naive_local();
+        // no DST change in between, the next wall clock time is the answer
+        let next = self.wall_times(naive).next()?;
+        if let LocalResult::Single(d) = self.tz.from_local_datetime(&next)
+            && d.offset().fix() == t.offset().fix()
+            && d > t
+        {
+            return Some(d);
+        }
+        // otherwise look at wall clock times around it, as they may repeat
+        let mut best: Option<DateTime<Z>> = None;
+        for n in self.wall_times(naive - DST_MARGIN) {
+            if let Some(ref b) = best
+                && n > b.naive_local() + DST_MARGIN
+            {
+                break;
+            }
+            for d in self.resolve(n) {
+                if d > t && best.as_ref().is_none_or(|b| d < *b) {
+                    best = Some(d);
+                }
+            }
+        }
+        best
+    }
+
+    /// times from now on
+    pub fn upcoming(&self) -> impl Iterator<Item = DateTime<Z>> + '_ {
+        let now = Utc::now().with_timezone(&self.tz);
+        std::iter::successors(self.after(&now), |t| self.after(t))
+    }
+
+    fn wall_times(&self, after: NaiveDateTime) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        self.schedule
+            .after(&Utc.from_utc_datetime(&after))
+            .map(|t| t.naive_utc())
+    }
+
+    fn resolve(&self, naive: NaiveDateTime) -> Vec<DateTime<Z>> {
+        match self.tz.from_local_datetime(&naive) {
+            LocalResult::Single(d) => vec![d],
+            LocalResult::Ambiguous(earlier, later) if self.every_hour => vec![earlier, later],
+            LocalResult::Ambiguous(earlier, _) => vec![earlier],
+            LocalResult::None if self.every_hour => vec![],
+            LocalResult::None => {
+                // shift by the offset in effect before the gap
+                let Some(before) = self
+                    .tz
+                    .from_local_datetime(&(naive - DST_MARGIN))
+                    .earliest()
+                else {
+                    return vec![];
+                };
+                let offset = before.offset().fix();
+                vec![self.tz.from_utc_datetime(&(naive - offset))]
+            }
+        }
+    }
+}
+
+/// longer than any DST change
+const DST_MARGIN: chrono::Duration = chrono::Duration::hours(3);
+
 pub async fn task_interval<F>(period: std::time::Duration, f: F)
 where
     F: Fn(),
@@ -67,12 +212,1125 @@ where
     }
 }
 
+/// What a scheduled job is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    /// runs are skipped until resumed
+    Paused,
+    /// stopped for good, after the in-flight run if any
+    Cancelled,
+}
+
+/// What to do when a run is due while the previous one is still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overlap {
+    /// drop the due run
+    #[default]
+    Skip,
+    /// run after the previous ones, in order, at most `MAX_QUEUED` wait and
+    /// later ones are dropped, cancelling drops the waiting ones
+    Queue,
+    /// run alongside
+    Concurrent,
+}
+
+/// runs of an `Overlap::Queue` job waiting for the previous one
+pub const MAX_QUEUED: usize = 16;
+
+/// missed runs caught up by `Misfire::RunAll` by default
+pub const MAX_CATCH_UP: usize = 100;
+
+/// What to do with runs missed while the process was down or too busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Misfire {
+    /// run once now for all of them
+    #[default]
+    RunOnce,
+    /// run each of them now, up to `JobOptions::max_catch_up`, one after
+    /// another unless the overlap is `Overlap::Concurrent`
+    RunAll,
+    /// wait for the next time
+    Skip,
+}
+
+/// How a job runs, see `Scheduler::cron_async`
+#[derive(Debug, Clone, Default)]
+pub struct JobOptions {
+    overlap: Overlap,
+    timeout: Option<Duration>,
+    misfire: Misfire,
+    max_catch_up: Option<usize>,
+    retry: Option<Retry>,
+    timezone: Option<Tz>,
+}
+
+impl JobOptions {
+    pub fn new() -> Self {
+        JobOptions::default()
+    }
+
+    pub fn overlap(mut self, overlap: Overlap) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// a run taking longer is dropped and logged as an error
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn misfire(mut self, misfire: Misfire) -> Self {
+        self.misfire = misfire;
+        self
+    }
+
+    /// with `Misfire::RunAll` run at most this many missed runs, then go on
+    /// from now, `MAX_CATCH_UP` by default
+    pub fn max_catch_up(mut self, max: usize) -> Self {
+        self.max_catch_up = Some(max);
+        self
+    }
+
+    /// retry failed runs, `timeout` covers all attempts of a run
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// cron times are in `timezone` instead of local time
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+}
+
+type RunFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+type Action = Arc<dyn Fn() -> RunFuture + Send + Sync>;
+
+fn action<F, Fut, E>(f: F, retry: Option<Retry>) -> Action
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Debug + Send + 'static,
+{
+    let f = Arc::new(f);
+    Arc::new(move || {
+        let (f, retry) = (f.clone(), retry.clone());
+        Box::pin(async move {
+            let res = match retry {
+                Some(retry) => retry.run(|| f()).await,
+                None => f().await,
+            };
+            res.map_err(|e| format!("{e:?}"))
+        })
+    })
+}
+
+/// An interval job with a zero period, which would run back-to-back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroInterval;
+
+impl std::fmt::Display for ZeroInterval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the interval of a job must be greater than zero")
+    }
+}
+
+impl std::error::Error for ZeroInterval {}
+
+enum Schedule {
+    Cron(Box<CronSchedule>),
+    Zoned(Box<CronSchedule<Tz>>),
+    Interval(chrono::Duration),
+}
+
+impl Schedule {
+    fn after(&self, t: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Cron(s) => s.after(&t),
+            Schedule::Zoned(s) => s
+                .after(&t.with_timezone(s.timezone()))
+                .map(|t| t.with_timezone(&Local)),
+            Schedule::Interval(period) => Some(t + *period),
+        }
+    }
+
+    /// the time to run after `last`, applying the misfire policy when that is
+    /// already past, `missed` counts the runs caught up so far
+    fn next(
+        &self,
+        last: DateTime<Local>,
+        now: DateTime<Local>,
+        options: &JobOptions,
+        missed: &mut usize,
+    ) -> Option<DateTime<Local>> {
+        let max = options.max_catch_up.unwrap_or(MAX_CATCH_UP);
+        match self.after(last) {
+            Some(next) if next < now => match options.misfire {
+                Misfire::RunOnce => Some(now),
+                Misfire::RunAll if *missed < max => {
+                    *missed += 1;
+                    Some(next)
+                }
+                Misfire::RunAll => {
+                    log::warn!("more than {max} runs missed, the others are skipped");
+                    *missed = 0;
+                    self.after(now)
+                }
+                Misfire::Skip => self.after(now),
+            },
+            next => {
+                *missed = 0;
+                next
+            }
+        }
+    }
+}
+
+/// Last run times of named jobs in SQLite, so runs missed while the process
+/// was down are caught up according to `Misfire`
+#[derive(Clone)]
+pub struct JobStore {
+    pool: SqlitePool,
+}
+
+impl JobStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        let sql = r#"
+            create table if not exists timer_job (
+                name text primary key,
+                last_run timestamp not null,
+                updated_at timestamp default current_timestamp
+            )
+        "#;
+        sqlx::query(sql).execute(&pool).await?;
+        Ok(JobStore { pool })
+    }
+
+    /// in `kit.db`, see `db::LITE_POOL`, needs a multi thread runtime
+    pub async fn lite() -> Result<Self, sqlx::Error> {
+        JobStore::new(crate::db::LITE_POOL.clone()).await
+    }
+
+    pub async fn last_run(&self, name: &str) -> Result<Option<DateTime<Local>>, sqlx::Error> {
+        sqlx::query_scalar("select last_run from timer_job where name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// keeps a later time already saved, as concurrent runs finish in any order
+    pub async fn save(&self, name: &str, last_run: DateTime<Local>) -> Result<(), sqlx::Error> {
+        // in UTC so the times compare as text
+        let sql = r#"
+            insert into timer_job (name, last_run) values (?, ?)
+            on conflict(name) do update set last_run = excluded.last_run, updated_at = current_timestamp
+            where excluded.last_run > timer_job.last_run
+        "#;
+        sqlx::query(sql)
+            .bind(name)
+            .bind(last_run.to_utc())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from timer_job where name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+struct Job {
+    name: String,
+    state: watch::Sender<JobState>,
+    next: Mutex<Option<DateTime<Local>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    runtime: Handle,
+}
+
+impl Job {
+    fn set_next(&self, next: Option<DateTime<Local>>) {
+        if let Ok(mut n) = self.next.lock() {
+            *n = next;
+        }
+    }
+
+    fn set_state(&self, state: JobState) {
+        self.state.send_if_modified(|s| {
+            // a cancelled job stays cancelled
+            let changed = *s != state && *s != JobState::Cancelled;
+            if changed {
+                *s = state;
+            }
+            changed
+        });
+    }
+
+    async fn join(&self) {
+        let task = self.task.lock().ok().and_then(|mut t| t.take());
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Block until the in-flight runs of `jobs` finish, for drop which can't await.
+/// On a current-thread runtime blocking would stall the runs themselves, so it only warns.
+fn join_blocking(jobs: &[Arc<Job>]) {
+    let running = |j: &&Arc<Job>| {
+        j.task
+            .lock()
+            .is_ok_and(|t| t.as_ref().is_some_and(|t| !t.is_finished()))
+    };
+    let Some(first) = jobs.iter().find(running) else {
+        return;
+    };
+    let join = async {
+        for job in jobs {
+            job.join().await;
+        }
+    };
+    match Handle::try_current() {
+        Ok(h) if h.runtime_flavor() == RuntimeFlavor::CurrentThread => log::warn!(
+            "job {} dropped on a current-thread runtime, in-flight runs are not awaited, \
+            use shutdown().await",
+            first.name
+        ),
+        Ok(h) => tokio::task::block_in_place(|| h.block_on(join)),
+        Err(_) => first.runtime.block_on(join),
+    }
+}
+
+/// Controls a job of a `Scheduler`, the job is cancelled when the handle is dropped
+/// unless `detach` was called.
+/// Drop blocks until the in-flight run finishes, except on a current-thread runtime
+/// where it can't and only warns, `shutdown().await` waits without blocking.
+#[must_use = "dropping a JobHandle cancels the job"]
+pub struct JobHandle {
+    job: Arc<Job>,
+    detached: bool,
+}
+
+impl JobHandle {
+    pub fn name(&self) -> &str {
+        &self.job.name
+    }
+
+    pub fn state(&self) -> JobState {
+        *self.job.state.borrow()
+    }
+
+    /// no more runs, an in-flight run finishes
+    pub fn cancel(&self) {
+        self.job.set_state(JobState::Cancelled);
+    }
+
+    pub fn pause(&self) {
+        self.job.set_state(JobState::Paused);
+    }
+
+    /// continue with the first time of the schedule after now
+    pub fn resume(&self) {
+        self.job.set_state(JobState::Running);
+    }
+
+    /// time of the next run, `None` when paused, cancelled or the schedule is over
+    pub fn next_run(&self) -> Option<DateTime<Local>> {
+        match self.state() {
+            JobState::Running => self.job.next.lock().ok().and_then(|n| *n),
+            _ => None,
+        }
+    }
+
+    /// cancel and wait for the in-flight run
+    pub async fn shutdown(self) {
+        self.cancel();
+        self.job.join().await;
+    }
+
+    /// keep the job running after the handle is dropped, until the scheduler shuts down
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        if !self.detached {
+            self.cancel();
+            join_blocking(std::slice::from_ref(&self.job));
+        }
+    }
+}
+
+/// Runs named jobs on the tokio runtime, each controlled by its `JobHandle`.
+/// Dropping it cancels every job and blocks until the in-flight runs finish, except on
+/// a current-thread runtime, see `JobHandle`; `shutdown().await` waits without blocking.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Mutex<Vec<Arc<Job>>>,
+    store: Option<JobStore>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    /// keep last run times of jobs in `store`, job names should be unique
+    pub fn store(mut self, store: JobStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// run `f` at the times of a cron expression with seconds, e.g. `0 */5 * * * *`
+    pub fn cron<F>(&self, name: &str, cron: &str, f: F) -> Result<JobHandle, cron::error::Error>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.cron_async(name, cron, JobOptions::default(), sync(f))
+    }
+
+    /// like `cron` with the times in `tz`
+    pub fn cron_tz<F>(
+        &self,
+        name: &str,
+        cron: &str,
+        tz: Tz,
+        f: F,
+    ) -> Result<JobHandle, cron::error::Error>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.cron_async(name, cron, JobOptions::new().timezone(tz), sync(f))
+    }
+
+    /// run `f` every `period`, the first time after one period, a zero period is an error
+    pub fn interval<F>(&self, name: &str, period: Duration, f: F) -> Result<JobHandle, ZeroInterval>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.interval_async(name, period, JobOptions::default(), sync(f))
+    }
+
+    /// like `cron` for async jobs, errors are logged
+    pub fn cron_async<F, Fut, E>(
+        &self,
+        name: &str,
+        cron: &str,
+        options: JobOptions,
+        f: F,
+    ) -> Result<JobHandle, cron::error::Error>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        let schedule = match options.timezone {
+            Some(tz) => Schedule::Zoned(Box::new(CronSchedule::new(cron, tz)?)),
+            None => Schedule::Cron(Box::new(CronSchedule::new(cron, Local)?)),
+        };
+        let action = action(f, options.retry.clone());
+        Ok(self.spawn(name, schedule, options, action))
+    }
+
+    /// like `interval` for async jobs, errors are logged
+    pub fn interval_async<F, Fut, E>(
+        &self,
+        name: &str,
+        period: Duration,
+        options: JobOptions,
+        f: F,
+    ) -> Result<JobHandle, ZeroInterval>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        if period.is_zero() {
+            return Err(ZeroInterval);
+        }
+        let period = chrono::Duration::from_std(period).unwrap_or(chrono::Duration::MAX);
+        let action = action(f, options.retry.clone());
+        Ok(self.spawn(name, Schedule::Interval(period), options, action))
+    }
+
+    fn spawn(
+        &self,
+        name: &str,
+        schedule: Schedule,
+        options: JobOptions,
+        action: Action,
+    ) -> JobHandle {
+        let job = Arc::new(Job {
+            name: name.to_string(),
+            state: watch::channel(JobState::Running).0,
+            next: Mutex::new(None),
+            task: Mutex::new(None),
+            runtime: Handle::current(),
+        });
+        let store = self.store.clone();
+        let task = tokio::spawn(run(job.clone(), schedule, options, action, store));
+        if let Ok(mut t) = job.task.lock() {
+            *t = Some(task);
+        }
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.retain(|j| *j.state.borrow() != JobState::Cancelled);
+            if jobs.iter().any(|j| j.name == name) {
+                log::warn!("job {name} is already scheduled");
+            }
+            jobs.push(job.clone());
+        }
+        JobHandle {
+            job,
+            detached: false,
+        }
+    }
+
+    /// names of the jobs not cancelled
+    pub fn jobs(&self) -> Vec<String> {
+        match self.jobs.lock() {
+            Ok(jobs) => jobs
+                .iter()
+                .filter(|j| *j.state.borrow() != JobState::Cancelled)
+                .map(|j| j.name.clone())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// cancel every job and wait for the in-flight runs
+    pub async fn shutdown(&self) {
+        let jobs = match self.jobs.lock() {
+            Ok(mut jobs) => std::mem::take(&mut *jobs),
+            Err(_) => vec![],
+        };
+        for job in &jobs {
+            job.set_state(JobState::Cancelled);
+        }
+        for job in &jobs {
+            job.join().await;
+        }
+    }
+
+    /// `shutdown` once `signal` completes, e.g. `tokio::signal::ctrl_c()`
+    pub async fn shutdown_on<S: Future>(&self, signal: S) {
+        signal.await;
+        log::info!("shutting down scheduled jobs ...");
+        self.shutdown().await;
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        let jobs = match self.jobs.get_mut() {
+            Ok(jobs) => jobs,
+            Err(e) => e.into_inner(),
+        };
+        for job in jobs.iter() {
+            job.set_state(JobState::Cancelled);
+        }
+        join_blocking(jobs);
+    }
+}
+
+fn sync<F: Fn()>(f: F) -> impl Fn() -> std::future::Ready<Result<(), String>> {
+    move || {
+        f();
+        std::future::ready(Ok(()))
+    }
+}
+
+async fn run(
+    job: Arc<Job>,
+    schedule: Schedule,
+    options: JobOptions,
+    action: Action,
+    store: Option<JobStore>,
+) {
+    let mut options = options;
+    // catching up runs one after another rather than only once
+    if options.misfire == Misfire::RunAll && options.overlap == Overlap::Skip {
+        options.overlap = Overlap::Queue;
+    }
+    let mut state = job.state.subscribe();
+    let mut runs = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(1));
+    let mut missed = 0;
+    let now = Local::now();
+    let last = match store {
+        Some(ref store) => match store.last_run(&job.name).await {
+            Ok(last) => last,
+            Err(e) => {
+                log::error!("load job {} error: {e:?}", job.name);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut next = match last {
+        Some(last) => schedule.next(last, now, &options, &mut missed),
+        None => schedule.after(now),
+    };
+    while let Some(at) = next {
+        job.set_next(Some(at));
+        let wait = (at - Local::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {
+                let current = *state.borrow_and_update();
+                match current {
+                    JobState::Running => {
+                        let run = Run { at, catch_up: missed > 0, store: store.clone() };
+                        start(&job, &options, &action, &permits, &mut runs, run);
+                    }
+                    JobState::Paused => (),
+                    JobState::Cancelled => break,
+                }
+                while runs.try_join_next().is_some() {}
+                next = schedule.next(at, Local::now(), &options, &mut missed);
+            }
+            changed = state.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                match *state.borrow_and_update() {
+                    JobState::Cancelled => break,
+                    // paused jobs sleep on until resumed, then start over from now
+                    JobState::Paused => job.set_next(None),
+                    JobState::Running => next = schedule.after(Local::now()),
+                }
+                while *state.borrow() == JobState::Paused {
+                    if state.changed().await.is_err() {
+                        break;
+                    }
+                    if *state.borrow_and_update() == JobState::Running {
+                        next = schedule.after(Local::now());
+                    }
+                }
+                if *state.borrow() == JobState::Cancelled {
+                    break;
+                }
+            }
+        }
+    }
+    job.set_next(None);
+    // queued runs fail to acquire and are dropped, the in-flight one finishes
+    permits.close();
+    while runs.join_next().await.is_some() {}
+}
+
+/// a due run, saved to `store` once it finished
+struct Run {
+    at: DateTime<Local>,
+    /// missed runs are bounded by `max_catch_up` instead of `MAX_QUEUED`
+    catch_up: bool,
+    store: Option<JobStore>,
+}
+
+/// start a run according to the overlap policy
+fn start(
+    job: &Job,
+    options: &JobOptions,
+    action: &Action,
+    permits: &Arc<Semaphore>,
+    runs: &mut JoinSet<()>,
+    run: Run,
+) {
+    let permit = match options.overlap {
+        Overlap::Skip => match permits.clone().try_acquire_owned() {
+            Ok(p) => Some(p),
+            Err(_) => {
+                log::warn!("job {} is still running, skipped", job.name);
+                return;
+            }
+        },
+        Overlap::Queue if !run.catch_up && runs.len() > MAX_QUEUED => {
+            log::warn!("job {} has {MAX_QUEUED} runs queued, skipped", job.name);
+            return;
+        }
+        _ => None,
+    };
+    let queue = (options.overlap == Overlap::Queue).then(|| permits.clone());
+    let (name, timeout, fut) = (job.name.clone(), options.timeout, action());
+    runs.spawn(async move {
+        let _permit = match queue {
+            Some(q) => match q.acquire_owned().await {
+                Ok(p) => Some(p),
+                Err(_) => return,
+            },
+            None => permit,
+        };
+        let result = match timeout {
+            Some(t) => match tokio::time::timeout(t, fut).await {
+                Ok(r) => r,
+                Err(_) => Err(format!("timed out after {t:?}")),
+            },
+            None => fut.await,
+        };
+        if let Err(e) = result {
+            log::error!("job {name} error: {e}");
+        }
+        if let Some(store) = run.store
+            && let Err(e) = store.save(&name, run.at).await
+        {
+            log::error!("save job {name} error: {e:?}");
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use chrono::Timelike;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_task_cron_interval() {
         //use super::*;
         //task_cron_interval("0/5 * * * * *", |dt| println!("task: {:?}", dt)).await;
     }
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn() + Send + Sync + 'static) {
+        let n = Arc::new(AtomicUsize::new(0));
+        let c = n.clone();
+        (n, move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_job_handle() {
+        let scheduler = Scheduler::new();
+        let (n, f) = counter();
+        let job = scheduler
+            .interval("count", Duration::from_millis(50), f)
+            .unwrap();
+        assert_eq!("count", job.name());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(job.next_run().is_some_and(|t| t > Local::now()));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(n.load(Ordering::SeqCst) >= 2);
+
+        job.pause();
+        assert_eq!(JobState::Paused, job.state());
+        assert_eq!(None, job.next_run());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let paused = n.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(paused, n.load(Ordering::SeqCst));
+
+        job.resume();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(n.load(Ordering::SeqCst) > paused);
+
+        job.cancel();
+        job.resume();
+        assert_eq!(JobState::Cancelled, job.state());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let cancelled = n.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(cancelled, n.load(Ordering::SeqCst));
+        assert_eq!(None, job.next_run());
+        assert!(scheduler.jobs().is_empty());
+
+        // dropping the handle cancels, detaching does not
+        let (n, f) = counter();
+        drop(
+            scheduler
+                .interval("dropped", Duration::from_millis(20), f)
+                .unwrap(),
+        );
+        let (m, g) = counter();
+        scheduler
+            .interval("detached", Duration::from_millis(20), g)
+            .unwrap()
+            .detach();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(0, n.load(Ordering::SeqCst));
+        assert!(m.load(Ordering::SeqCst) > 0);
+        assert_eq!(vec!["detached".to_string()], scheduler.jobs());
+        scheduler.shutdown().await;
+        assert!(scheduler.jobs().is_empty());
+    }
+
+    fn slow_job(
+        running: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+        runs: Arc<AtomicUsize>,
+    ) -> impl Fn() -> RunFuture + Send + Sync + 'static {
+        move || {
+            let (running, max, runs) = (running.clone(), max.clone(), runs.clone());
+            Box::pin(async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(120)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_overlap() {
+        let scheduler = Scheduler::new();
+        let mut results = vec![];
+        for overlap in [Overlap::Skip, Overlap::Queue, Overlap::Concurrent] {
+            let (running, max, runs) = (
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            );
+            let job = scheduler
+                .interval_async(
+                    "slow",
+                    Duration::from_millis(40),
+                    JobOptions::new().overlap(overlap),
+                    slow_job(running, max.clone(), runs.clone()),
+                )
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(430)).await;
+            // waits for the in-flight runs
+            job.shutdown().await;
+            results.push((max.load(Ordering::SeqCst), runs.load(Ordering::SeqCst)));
+        }
+        let [
+            (skip_max, skip),
+            (queue_max, queue),
+            (concurrent_max, concurrent),
+        ] = results[..]
+        else {
+            unreachable!()
+        };
+        assert_eq!(1, skip_max);
+        assert_eq!(1, queue_max);
+        assert!(concurrent_max > 1);
+        assert!(skip <= queue);
+        assert!(queue < concurrent);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_queue() {
+        let scheduler = Scheduler::new();
+        let (running, max, runs) = (
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let job = scheduler
+            .interval_async(
+                "queue",
+                Duration::from_millis(20),
+                JobOptions::new().overlap(Overlap::Queue),
+                slow_job(running.clone(), max, runs.clone()),
+            )
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // several runs are queued by now, only the in-flight one finishes
+        let started = runs.load(Ordering::SeqCst) + running.load(Ordering::SeqCst);
+        job.shutdown().await;
+        assert_eq!(started, runs.load(Ordering::SeqCst));
+        assert!(started <= 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_timeout() {
+        let scheduler = Scheduler::new();
+        let (started, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (s, f) = (started.clone(), finished.clone());
+        let job = scheduler
+            .cron_async(
+                "timeout",
+                "* * * * * *",
+                JobOptions::new().timeout(Duration::from_millis(50)),
+                move || {
+                    let (s, f) = (s.clone(), f.clone());
+                    async move {
+                        s.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        f.fetch_add(1, Ordering::SeqCst);
+                        Err::<(), _>("never here")
+                    }
+                },
+            )
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+        job.shutdown().await;
+        assert!(started.load(Ordering::SeqCst) >= 1);
+        assert_eq!(0, finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_job_retry() {
+        let scheduler = Scheduler::new();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let a = attempts.clone();
+        let retry = Retry::new()
+            .attempts(5)
+            .backoff(Duration::from_millis(5), Duration::from_millis(5))
+            .retry_on(|e: &&str| *e == "busy");
+        let job = scheduler
+            .interval_async(
+                "retry",
+                Duration::from_millis(100),
+                JobOptions::new().retry(retry),
+                move || {
+                    let a = a.clone();
+                    async move {
+                        match a.fetch_add(1, Ordering::SeqCst) {
+                            0 | 1 => Err("busy"),
+                            2 => Ok(()),
+                            _ => Err("broken"),
+                        }
+                    }
+                },
+            )
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+        // a non retryable error fails the run at once
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        job.shutdown().await;
+        assert_eq!(4, attempts.load(Ordering::SeqCst));
+    }
+
+    fn times(cron: &str, tz: Tz, from: &str, n: usize) -> Vec<String> {
+        let schedule = CronSchedule::new(cron, tz).unwrap();
+        let from = NaiveDateTime::parse_from_str(from, "%Y-%m-%d %H:%M:%S").unwrap();
+        let from = tz.from_local_datetime(&from).earliest().unwrap();
+        std::iter::successors(schedule.after(&from), |t| schedule.after(t))
+            .take(n)
+            .map(|t| t.to_rfc3339())
+            .collect()
+    }
+
+    #[test]
+    fn test_cron_tz() {
+        let tz: Tz = "Asia/Shanghai".parse().unwrap();
+        let t = times("0 0 9 * * *", tz, "2024-06-01 12:00:00", 1);
+        assert_eq!(vec!["2024-06-02T09:00:00+08:00"], t);
+        let next = Schedule::Zoned(Box::new(CronSchedule::new("0 0 9 * * *", tz).unwrap()))
+            .after(Local::now())
+            .unwrap();
+        assert_eq!(1, next.to_utc().hour());
+    }
+
+    #[test]
+    fn test_cron_dst() {
+        let ny = chrono_tz::America::New_York;
+        // 2024-03-10 02:00 EST jumps to 03:00 EDT, the skipped 02:30 runs at 03:30
+        let t = times("0 30 2 * * *", ny, "2024-03-09 12:00:00", 2);
+        assert_eq!(
+            vec!["2024-03-10T03:30:00-04:00", "2024-03-11T02:30:00-04:00"],
+            t
+        );
+        let t = times("0 0 2,3 * * *", ny, "2024-03-10 00:00:00", 2);
+        assert_eq!(
+            vec!["2024-03-10T03:00:00-04:00", "2024-03-11T02:00:00-04:00"],
+            t
+        );
+        // every hour schedules keep real time
+        let t = times("0 */30 * * * *", ny, "2024-03-10 01:15:00", 3);
+        assert_eq!(
+            vec![
+                "2024-03-10T01:30:00-05:00",
+                "2024-03-10T03:00:00-04:00",
+                "2024-03-10T03:30:00-04:00"
+            ],
+            t
+        );
+
+        // 2024-11-03 02:00 EDT goes back to 01:00 EST, the repeated 01:30 runs once
+        let t = times("0 30 1 * * *", ny, "2024-11-02 12:00:00", 2);
+        assert_eq!(
+            vec!["2024-11-03T01:30:00-04:00", "2024-11-04T01:30:00-05:00"],
+            t
+        );
+        let t = times("0 */30 * * * *", ny, "2024-11-03 00:45:00", 5);
+        assert_eq!(
+            vec![
+                "2024-11-03T01:00:00-04:00",
+                "2024-11-03T01:30:00-04:00",
+                "2024-11-03T01:00:00-05:00",
+                "2024-11-03T01:30:00-05:00",
+                "2024-11-03T02:00:00-05:00"
+            ],
+            t
+        );
+    }
+
+    async fn memory_store() -> JobStore {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        JobStore::new(pool).await.unwrap()
+    }
+
+    #[test]
+    fn test_misfire() {
+        let now = Local::now();
+        let schedule = Schedule::Interval(chrono::Duration::seconds(10));
+        let next = |last, misfire| {
+            let options = JobOptions::new().misfire(misfire).max_catch_up(2);
+            schedule.next(last, now, &options, &mut 0)
+        };
+        let last = now - chrono::Duration::seconds(35);
+        assert_eq!(Some(now), next(last, Misfire::RunOnce));
+        assert_eq!(
+            Some(last + chrono::Duration::seconds(10)),
+            next(last, Misfire::RunAll)
+        );
+        assert_eq!(
+            Some(now + chrono::Duration::seconds(10)),
+            next(last, Misfire::Skip)
+        );
+        let last = now - chrono::Duration::seconds(5);
+        for misfire in [Misfire::RunOnce, Misfire::RunAll, Misfire::Skip] {
+            assert_eq!(
+                Some(last + chrono::Duration::seconds(10)),
+                next(last, misfire)
+            );
+        }
+
+        // 3 runs missed, 2 caught up
+        let options = JobOptions::new().misfire(Misfire::RunAll).max_catch_up(2);
+        let (mut missed, mut last) = (0, now - chrono::Duration::seconds(35));
+        let mut times = vec![];
+        while let Some(t) = schedule.next(last, now, &options, &mut missed) {
+            times.push((t - now).num_seconds());
+            if t > now {
+                break;
+            }
+            last = t;
+        }
+        assert_eq!(vec![-25, -15, 10], times);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_job_store() {
+        let store = memory_store().await;
+        let scheduler = Scheduler::new().store(store.clone());
+        let mut counts = vec![];
+        for (name, options) in [
+            ("once", JobOptions::new().misfire(Misfire::RunOnce)),
+            ("all", JobOptions::new().misfire(Misfire::RunAll)),
+            ("skip", JobOptions::new().misfire(Misfire::Skip)),
+            (
+                "capped",
+                JobOptions::new().misfire(Misfire::RunAll).max_catch_up(3),
+            ),
+        ] {
+            // down for 5.5 periods
+            let last = Local::now() - chrono::Duration::milliseconds(5500);
+            store.save(name, last).await.unwrap();
+            let (n, f) = counter();
+            let job = scheduler
+                .interval_async(name, Duration::from_secs(1), options, sync(f))
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            job.shutdown().await;
+            counts.push(n.load(Ordering::SeqCst));
+            let saved = store.last_run(name).await.unwrap().unwrap();
+            assert_eq!(name != "skip", saved > last);
+        }
+        // all missed runs even with the default `Overlap::Skip`
+        assert_eq!(vec![1, 5, 0, 3], counts);
+
+        assert_eq!(None, store.last_run("new").await.unwrap());
+        store.remove("once").await.unwrap();
+        assert_eq!(None, store.last_run("once").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_graceful_shutdown() {
+        let scheduler = Arc::new(Scheduler::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let d = done.clone();
+        let job = scheduler
+            .interval("slow", Duration::from_millis(20), move || {
+                std::thread::sleep(Duration::from_millis(300));
+                d.store(true, Ordering::SeqCst);
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        job.shutdown().await;
+        assert!(done.load(Ordering::SeqCst));
+
+        let (n, f) = counter();
+        let _job = scheduler.cron("cron", "* * * * * *", f).unwrap();
+        assert!(scheduler.cron("bad", "every minute", || ()).is_err());
+        assert_eq!(
+            Some(ZeroInterval),
+            scheduler.interval("busy", Duration::ZERO, || ()).err()
+        );
+        assert_eq!(vec!["cron"], scheduler.jobs());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let s = scheduler.clone();
+        let shutdown = tokio::spawn(async move { s.shutdown_on(rx).await });
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        tx.send(()).unwrap();
+        shutdown.await.unwrap();
+        let runs = n.load(Ordering::SeqCst);
+        assert!(runs >= 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(runs, n.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drop_waits() {
+        let slow = |done: Arc<AtomicBool>| {
+            move || {
+                std::thread::sleep(Duration::from_millis(200));
+                done.store(true, Ordering::SeqCst);
+            }
+        };
+        let scheduler = Scheduler::new();
+        let done = Arc::new(AtomicBool::new(false));
+        let job = scheduler
+            .interval("slow", Duration::from_millis(10), slow(done.clone()))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(job);
+        assert!(done.load(Ordering::SeqCst));
+
+        let done = Arc::new(AtomicBool::new(false));
+        scheduler
+            .interval("detached", Duration::from_millis(10), slow(done.clone()))
+            .unwrap()
+            .detach();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(scheduler);
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_drop_scheduler() {
+        let scheduler = Scheduler::new();
+        let (n, f) = counter();
+        scheduler
+            .interval("detached", Duration::from_millis(10), f)
+            .unwrap()
+            .detach();
+        let job = scheduler
+            .interval("held", Duration::from_millis(10), || ())
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(scheduler);
+        assert_eq!(JobState::Cancelled, job.state());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let runs = n.load(Ordering::SeqCst);
+        assert!(runs >= 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs, n.load(Ordering::SeqCst));
+    }
 }
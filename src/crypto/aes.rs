@@ -105,6 +105,18 @@ pub fn decrypt_aes_gcm_256(secret: &[u8], nonce: &[u8], data: &[u8]) -> Vec<u8>
     cipher.decrypt(&nonce, data).unwrap()
 }
 
+/// decrypt gcm 256, returns an error instead of panicking on a wrong key or tampered data
+pub fn try_decrypt_aes_gcm_256(
+    secret: &[u8],
+    nonce: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, aes_gcm::Error> {
+    let key = Key::<Aes256Gcm>::from_slice(secret);
+    let nonce = Nonce::from_slice(nonce);
+    let cipher = Aes256Gcm::new(key);
+    cipher.decrypt(nonce, data)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
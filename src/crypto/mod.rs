@@ -3,3 +3,4 @@ pub mod ecc;
 pub mod ecdh_k256;
 pub mod ecdh_p256;
 pub mod rsa;
+pub mod secret;
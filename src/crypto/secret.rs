@@ -0,0 +1,97 @@
+use anyhow::{Error, Result};
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use super::aes::{
+    aes_gcm_key_256, aes_gcm_nonce_256, encrypt_aes_gcm_256, try_decrypt_aes_gcm_256,
+};
+
+/// prefix of encrypted config values, `enc:v1:` + base64(nonce + ciphertext)
+pub const SECRET_PREFIX: &str = "enc:v1:";
+/// base64 master key
+pub const MASTER_KEY_ENV: &str = "APP_MASTER_KEY";
+/// path of a file holding the base64 master key
+pub const MASTER_KEY_FILE_ENV: &str = "APP_MASTER_KEY_FILE";
+
+const NONCE_LEN: usize = 12;
+
+/// generate a base64 aes 256 master key
+pub fn gen_master_key() -> String {
+    BASE64_STANDARD.encode(aes_gcm_key_256())
+}
+
+/// master key from `APP_MASTER_KEY`, or the file named by `APP_MASTER_KEY_FILE`
+pub fn master_key() -> Result<Vec<u8>> {
+    master_key_from(|k| std::env::var(k).ok())
+}
+
+/// like `master_key` with the variables looked up by `var`
+pub fn master_key_from<F: Fn(&str) -> Option<String>>(var: F) -> Result<Vec<u8>> {
+    let key = match var(MASTER_KEY_ENV) {
+        Some(key) => key,
+        None => match var(MASTER_KEY_FILE_ENV) {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => {
+                return Err(Error::msg(format!(
+                    "{MASTER_KEY_ENV} or {MASTER_KEY_FILE_ENV} is not set"
+                )));
+            }
+        },
+    };
+    let key = BASE64_STANDARD.decode(key.trim())?;
+    check_key(&key)?;
+    Ok(key)
+}
+
+pub fn is_secret(s: &str) -> bool {
+    s.starts_with(SECRET_PREFIX)
+}
+
+/// encrypt a value for pasting into a config file
+pub fn encrypt_secret(key: &[u8], plain: &str) -> Result<String> {
+    check_key(key)?;
+    let mut data = aes_gcm_nonce_256();
+    data.extend(encrypt_aes_gcm_256(key, &data, plain.as_bytes()));
+    Ok(format!("{}{}", SECRET_PREFIX, BASE64_STANDARD.encode(data)))
+}
+
+/// decrypt an `enc:v1:` value
+pub fn decrypt_secret(key: &[u8], s: &str) -> Result<String> {
+    check_key(key)?;
+    let data = match s.strip_prefix(SECRET_PREFIX) {
+        Some(data) => BASE64_STANDARD.decode(data)?,
+        None => return Err(Error::msg("not an encrypted value")),
+    };
+    if data.len() < NONCE_LEN {
+        return Err(Error::msg("encrypted value too short"));
+    }
+    let (nonce, data) = data.split_at(NONCE_LEN);
+    let plain = try_decrypt_aes_gcm_256(key, nonce, data)
+        .map_err(|_| Error::msg("decrypt error, wrong key or tampered value"))?;
+    Ok(String::from_utf8(plain)?)
+}
+
+fn check_key(key: &[u8]) -> Result<()> {
+    match key.len() {
+        32 => Ok(()),
+        n => Err(Error::msg(format!("master key must be 32 bytes, got {n}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let key = BASE64_STANDARD.decode(gen_master_key()).unwrap();
+        let enc = encrypt_secret(&key, "123456").unwrap();
+        assert!(is_secret(&enc));
+        assert_ne!(enc, encrypt_secret(&key, "123456").unwrap());
+        assert_eq!("123456", decrypt_secret(&key, &enc).unwrap());
+
+        let other = BASE64_STANDARD.decode(gen_master_key()).unwrap();
+        assert!(decrypt_secret(&other, &enc).is_err());
+        assert!(decrypt_secret(&key, "enc:v1:AAAA").is_err());
+        assert!(encrypt_secret(b"short", "x").is_err());
+    }
+}
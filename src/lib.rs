@@ -4,8 +4,19 @@ pub mod crypto;
 
 pub use base::*;
 
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, RwLock},
+};
+
+use base::conf::{Settings, UnknownKey};
+use base::logs::{
+    CaptureGuard, Compress, Directives, Discard, JsonFormat, LogCapture, LogFilter, LogFormat,
+    LogHandle, LogRolling, Period, Redactor,
+};
+use crypto::secret::{MASTER_KEY_ENV, MASTER_KEY_FILE_ENV, master_key_from};
 use fast_log::{
-    Config, Logger,
+    Config,
     consts::LogSize,
     error::LogError,
     plugin::{
@@ -16,6 +27,7 @@ use fast_log::{
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 
+pub use fast_log;
 pub use log;
 
 #[cfg(debug_assertions)]
@@ -29,7 +41,25 @@ pub struct Log {
     pub roll: Rolling,
     pub keep: KeepType,
     pub packer: LogPacker,
+    /// replaces `roll` when set, see `rolling`
+    pub rolling: Option<LogRolling>,
+    /// replaces `packer` when set, see `compress`
+    pub compress: Option<Compress>,
     pub level: LevelFilter,
+    /// `RUST_LOG` style directives, e.g. `rskit::base::db=debug,hyper=warn,info`
+    pub directives: Option<String>,
+    pub format: LogFormat,
+    pub fields: BTreeMap<String, String>,
+    /// also print to console in `init_file` and `init_split`
+    pub console: bool,
+    /// mask sensitive values before records are written
+    pub redactor: Option<Redactor>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log::new()
+    }
 }
 
 impl Log {
@@ -44,66 +74,460 @@ impl Log {
             roll: Rolling::new(RollingType::BySize(LogSize::MB(100))),
             keep: KeepType::KeepNum(10),
             packer: LogPacker {},
+            rolling: None,
+            compress: None,
             level: RUST_LOG,
+            directives: std::env::var("RUST_LOG").ok(),
+            format: LogFormat::Text,
+            fields: BTreeMap::new(),
+            console: true,
+            redactor: None,
         }
     }
 
-    pub fn init(&self) -> Result<&'static Logger, LogError> {
-        fast_log::init(
-            Config::new()
-                .level(self.level)
-                .chan_len(self.chan)
-                .console(),
-        )
+    pub fn from_config(config: &LogConfig) -> Result<Self, String> {
+        Log::new().with_config(config)
     }
 
-    pub fn init_file(&self) -> Result<&'static Logger, LogError> {
-        fast_log::init(
-            Config::new()
-                .level(self.level)
-                .chan_len(self.chan)
-                .file(&self.path)
-                .console(),
-        )
+    /// build from the `[log]` section, defaults when settings or section are missing,
+    /// e.g. `Log::from_settings(&conf::AUTO_CONFIG)`
+    pub fn from_settings(settings: &RwLock<Option<Settings>>) -> Result<Self, String> {
+        let settings = settings.read().map_err(|e| format!("{e}"))?;
+        match settings.as_ref().and_then(|s| s.log.as_ref()) {
+            Some(config) => Log::from_config(config),
+            None => Ok(Log::new()),
+        }
     }
 
-    pub fn init_split(self) -> Result<&'static Logger, LogError> {
-        fast_log::init(
-            fast_log::Config::new()
-                .level(self.level)
-                .chan_len(self.chan)
-                .file_split(&self.path, self.roll, self.keep, self.packer)
-                .console(),
-        )
+    /// e.g. `LogRolling::daily().max_size(LogSize::MB(100))`
+    pub fn rolling(mut self, roll: LogRolling) -> Self {
+        self.rolling = Some(roll);
+        self
+    }
+
+    /// e.g. `KeepType::KeepTime(Duration::from_secs(30 * 24 * 3600))`
+    pub fn keep(mut self, keep: KeepType) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn compress(mut self, compress: Compress) -> Self {
+        self.compress = Some(compress);
+        self
+    }
+
+    /// apply the fields set in a `[log]` config section
+    pub fn with_config(mut self, config: &LogConfig) -> Result<Self, String> {
+        if let Some(ref path) = config.path {
+            self.path = path.clone();
+        }
+        if let Some(console) = config.console {
+            self.console = console;
+        }
+        if let Some(format) = config.format {
+            self.format = format;
+        }
+        if let Some(ref level) = config.level {
+            self.level = level
+                .parse()
+                .map_err(|_| format!("invalid log level: {level}"))?;
+        }
+        if config.directives.is_some() {
+            self.directives = config.directives.clone();
+        }
+        let size = match config.max_size {
+            Some(ref s) => Some(LogSize::parse(s).map_err(|e| format!("{e}"))?),
+            None => None,
+        };
+        match (config.rolling, size) {
+            (Some(period), Some(size)) => {
+                self.rolling = Some(LogRolling::period(period).max_size(size))
+            }
+            (Some(period), None) => self.rolling = Some(LogRolling::period(period)),
+            (None, Some(size)) => self.rolling = Some(LogRolling::size(size)),
+            (None, None) => (),
+        }
+        if let Some(days) = config.keep_days {
+            self.keep = KeepType::KeepTime(std::time::Duration::from_secs(days * 24 * 3600));
+        } else if let Some(num) = config.keep_num {
+            self.keep = KeepType::KeepNum(num);
+        }
+        if config.compress.is_some() {
+            self.compress = config.compress;
+        }
+        match (config.redact, config.redact_keys.as_ref()) {
+            (Some(false), _) => self.redactor = None,
+            (Some(true), None) => self.redactor = Some(Redactor::default()),
+            (_, Some(keys)) => {
+                self.redactor = Some(keys.iter().fold(Redactor::default(), |r, k| r.key(k)))
+            }
+            (None, None) => (),
+        }
+        Ok(self)
+    }
+
+    /// e.g. `Redactor::default().key("pin")`
+    pub fn redact(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    pub fn directives(mut self, directives: &str) -> Self {
+        self.directives = Some(directives.to_string());
+        self
+    }
+
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// static field written with every json record
+    pub fn field(mut self, key: &str, value: &str) -> Self {
+        self.fields.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// the directives are installed by `install` once the logger is
+    fn config(&self) -> (Config, Directives) {
+        let directives = match self.directives {
+            Some(ref s) => Directives::parse(s, self.level).unwrap_or_else(|e| {
+                eprintln!("log directives error: {e}");
+                Directives::new(self.level)
+            }),
+            None => Directives::new(self.level),
+        };
+        let level = directives.max_level();
+
+        let json = match self.format {
+            LogFormat::Text => None,
+            LogFormat::Json => Some(
+                self.fields
+                    .iter()
+                    .fold(JsonFormat::new(), |json, (k, v)| json.field(k, v)),
+            ),
+        };
+        let config = Config::new()
+            .level(level)
+            .chan_len(self.chan)
+            .add_filter(LogFilter {
+                json,
+                redactor: self.redactor.clone(),
+            });
+        (config, directives)
+    }
+
+    fn install(config: Config, directives: Directives) -> Result<LogHandle, LogError> {
+        let logger = fast_log::init(config)?;
+        base::logs::set_directives(directives);
+        Ok(LogHandle::new(logger))
+    }
+
+    pub fn init(&self) -> Result<LogHandle, LogError> {
+        let (config, directives) = self.config();
+        Log::install(config.console(), directives)
+    }
+
+    /// Record the logs of the current thread into memory, for asserting on logs in tests.
+    /// Installs a logger writing nowhere if none is installed yet, otherwise records go
+    /// through the existing one, whose level and directives then apply.
+    /// Capturing stops when the guard drops.
+    pub fn init_capture(&self) -> Result<CaptureGuard, LogError> {
+        static INSTALL: Mutex<()> = Mutex::new(());
+        let _lock = INSTALL.lock().unwrap_or_else(|e| e.into_inner());
+        if fast_log::logger().cfg.get().is_none() {
+            let (config, directives) = self.config();
+            Log::install(config.add_appender(Discard), directives)?;
+        }
+        Ok(LogCapture::new().scoped())
+    }
+
+    pub fn init_file(&self) -> Result<LogHandle, LogError> {
+        let (config, directives) = self.config();
+        let config = config.file(&self.path);
+        let config = if self.console {
+            config.console()
+        } else {
+            config
+        };
+        Log::install(config, directives)
+    }
+
+    pub fn init_split(self) -> Result<LogHandle, LogError> {
+        let (config, directives) = self.config();
+        let config = match (self.rolling, self.compress) {
+            (Some(roll), Some(compress)) => {
+                config.file_split(&self.path, roll, self.keep, compress)
+            }
+            (Some(roll), None) => config.file_split(&self.path, roll, self.keep, self.packer),
+            (None, Some(compress)) => config.file_split(&self.path, self.roll, self.keep, compress),
+            (None, None) => config.file_split(&self.path, self.roll, self.keep, self.packer),
+        };
+        let config = if self.console {
+            config.console()
+        } else {
+            config
+        };
+        Log::install(config, directives)
     }
 }
 
+/// The `[log]` section of a config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// log file, relative to the working directory
+    pub path: Option<String>,
+    /// print to console as well, default true
+    pub console: Option<bool>,
+    /// `text` or `json`
+    pub format: Option<LogFormat>,
+    /// default level, e.g. `info`
+    pub level: Option<String>,
+    /// `RUST_LOG` style directives
+    pub directives: Option<String>,
+    /// `hourly` or `daily`, combined with `max_size` whichever comes first
+    pub rolling: Option<Period>,
+    /// e.g. `100MB`
+    pub max_size: Option<String>,
+    /// keep rolled files for this many days, takes precedence over `keep_num`
+    pub keep_days: Option<u64>,
+    pub keep_num: Option<i64>,
+    /// `none`, `gzip` or `zstd`
+    pub compress: Option<Compress>,
+    /// mask passwords, secrets, tokens, card numbers and emails
+    pub redact: Option<bool>,
+    /// extra keys to mask, enables redaction
+    pub redact_keys: Option<Vec<String>>,
+}
+
+impl LogConfig {
+    pub fn directives(&self) -> Result<Directives, String> {
+        let level = match self.level {
+            Some(ref l) => l
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("invalid log level: {l}"))?,
+            None => RUST_LOG,
+        };
+        match self.directives {
+            Some(ref d) => Directives::parse(d, level),
+            None => Ok(Directives::new(level)),
+        }
+    }
+}
+
+/// Layered config, later layers override earlier ones:
+/// 1. embedded defaults, see `defaults`
+/// 2. `<name>`, the format is detected by extension, `app` finds `app.toml`, `app.yaml`, ...
+/// 3. `<name>.<profile>`, profile from `profile` or the `APP_ENV` variable
+/// 4. files added by `file`, in order
+/// 5. files of directories added by `dir`, e.g. `conf.d`, in name order
+/// 6. environment variables `APP__SECTION__KEY`, including those set in `.env`
 pub struct Configs<T: Serialize + Deserialize<'static>> {
     config: Option<T>,
+    format: Option<config::FileFormat>,
+    files: Vec<String>,
+    dirs: Vec<String>,
+    defaults: Option<String>,
+    profile: Option<String>,
+    env_prefix: String,
+    dotenv: bool,
+    env: Option<config::Map<String, String>>,
+    master_key: Option<Vec<u8>>,
+    strict: bool,
+    unknown: Vec<UnknownKey>,
+    sources: BTreeMap<String, String>,
+}
+
+impl<T: Serialize + Deserialize<'static>> Default for Configs<T> {
+    fn default() -> Self {
+        Configs::new()
+    }
 }
 
 impl<T: Serialize + Deserialize<'static>> Configs<T> {
     pub fn new() -> Self {
-        Configs { config: None }
+        Configs {
+            config: None,
+            format: None,
+            files: vec![],
+            dirs: vec![],
+            defaults: None,
+            profile: None,
+            env_prefix: "APP".to_string(),
+            dotenv: true,
+            env: None,
+            master_key: None,
+            strict: false,
+            unknown: vec![],
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// format of `<name>` and `<name>.<profile>` when the extension does not tell
+    pub fn format(mut self, format: config::FileFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// a required file merged over `<name>`, format by extension
+    pub fn file(mut self, path: &str) -> Self {
+        self.files.push(path.to_string());
+        self
+    }
+
+    /// a directory whose config files are merged in name order
+    pub fn dir(mut self, path: &str) -> Self {
+        self.dirs.push(path.to_string());
+        self
+    }
+
+    /// toml used as the lowest layer, e.g. `include_str!("default.toml")`
+    pub fn defaults(mut self, toml: &str) -> Self {
+        self.defaults = Some(toml.to_string());
+        self
+    }
+
+    /// overrides `APP_ENV`
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    /// prefix of override variables, `APP` by default
+    pub fn env_prefix(mut self, prefix: &str) -> Self {
+        self.env_prefix = prefix.to_string();
+        self
+    }
+
+    /// load `.env` into the environment first, on by default
+    pub fn dotenv(mut self, dotenv: bool) -> Self {
+        self.dotenv = dotenv;
+        self
+    }
+
+    /// read `APP_ENV`, `APP_MASTER_KEY` and the override variables from `vars` instead of
+    /// the process environment, e.g. in tests where setting variables races with other threads
+    pub fn env(mut self, vars: config::Map<String, String>) -> Self {
+        self.env = Some(vars);
+        self
+    }
+
+    /// key to decrypt `enc:v1:` values, by default read from
+    /// `APP_MASTER_KEY` or `APP_MASTER_KEY_FILE` when a secret is found
+    pub fn master_key(mut self, key: &[u8]) -> Self {
+        self.master_key = Some(key.to_vec());
+        self
+    }
+
+    /// fail `init` on keys that `T` does not know, see `unknown_keys`,
+    /// they are logged as warnings, otherwise only at debug level
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// keys found by the last `init` that `T` does not know, with file and line
+    pub fn unknown_keys(&self) -> &[UnknownKey] {
+        &self.unknown
+    }
+
+    /// where each final value came from, keyed by dotted path, e.g. `app.version`
+    pub fn sources(&self) -> &BTreeMap<String, String> {
+        &self.sources
+    }
+
+    pub fn source(&self, key: &str) -> Option<&str> {
+        self.sources.get(key).map(String::as_str)
     }
+
     pub fn init(&mut self, name: Option<String>) -> Option<&T> {
-        match config::Config::builder()
-            .add_source(
-                config::File::with_name(&format!("{}.toml", name.unwrap_or("app".to_string())))
-                    .required(false),
-            )
-            .build()
-        {
-            Ok(cfg) => match cfg.try_deserialize::<T>() {
-                Ok(s) => {
-                    self.config = Some(s);
-                    return self.config.as_ref();
+        if self.dotenv {
+            let _ = dotenvy::dotenv();
+        }
+        let name = name.unwrap_or("app".to_string());
+        let profile = self.profile.clone().or_else(|| match self.env {
+            Some(ref vars) => vars.get("APP_ENV").cloned(),
+            None => std::env::var("APP_ENV").ok(),
+        });
+
+        let mut builder = config::Config::builder();
+        if let Some(ref defaults) = self.defaults {
+            builder =
+                builder.add_source(config::File::from_str(defaults, config::FileFormat::Toml));
+        }
+        let mut names = vec![name.clone()];
+        if let Some(ref profile) = profile {
+            names.push(profile_name(&name, profile));
+        }
+        for name in names {
+            let file = config::File::with_name(&name).required(false);
+            builder = match self.format {
+                Some(format) => builder.add_source(file.format(format)),
+                None => builder.add_source(file),
+            };
+        }
+        for file in &self.files {
+            builder = builder.add_source(config::File::with_name(file));
+        }
+        for dir in &self.dirs {
+            match base::conf::dir_files(dir) {
+                Ok(files) => {
+                    for file in files {
+                        builder = builder.add_source(config::File::from(file));
+                    }
                 }
-                Err(e) => {
-                    log::error!("deserialize config error: {:?}", e);
-                    None
+                Err(e) => log::error!("read config dir {dir} error: {e:?}"),
+            }
+        }
+        builder = builder.add_source(
+            config::Environment::with_prefix(&self.env_prefix)
+                .prefix_separator("__")
+                .separator("__")
+                .source(self.env.clone()),
+        );
+
+        match builder.build() {
+            Ok(cfg) => {
+                self.sources.clear();
+                collect_sources("", &cfg.cache, &self.env_prefix, &mut self.sources);
+                let master_key = match (&self.master_key, &self.env) {
+                    (None, Some(vars))
+                        if vars.contains_key(MASTER_KEY_ENV)
+                            || vars.contains_key(MASTER_KEY_FILE_ENV) =>
+                    {
+                        match master_key_from(|k| vars.get(k).cloned()) {
+                            Ok(key) => Some(key),
+                            Err(e) => {
+                                log::error!("master key error: {e}");
+                                return None;
+                            }
+                        }
+                    }
+                    (key, _) => key.clone(),
+                };
+                match base::conf::deserialize_strict::<T>(cfg, master_key.as_deref()) {
+                    Ok((s, unknown)) => {
+                        for key in &unknown {
+                            if self.strict {
+                                log::warn!("{}", key);
+                            } else {
+                                log::debug!("{}", key);
+                            }
+                        }
+                        self.unknown = unknown;
+                        if self.strict && !self.unknown.is_empty() {
+                            log::error!("config has {} unknown keys", self.unknown.len());
+                            return None;
+                        }
+                        self.config = Some(s);
+                        return self.config.as_ref();
+                    }
+                    Err(e) => {
+                        log::error!("deserialize config error: {:?}", e);
+                        None
+                    }
                 }
-            },
+            }
             Err(e) => {
                 log::error!("init config error: {:?}", e);
                 None
@@ -112,6 +536,115 @@ impl<T: Serialize + Deserialize<'static>> Configs<T> {
     }
 }
 
+impl<T: Serialize + Deserialize<'static> + Default> Configs<T> {
+    /// `T::default()` as toml, a reference config to ship as `app.example.toml`.
+    /// Toml has no null, so `None` fields are written as commented-out keys, e.g. `# log =`.
+    pub fn example() -> Result<String, String> {
+        let value = T::default();
+        let text = toml::to_string_pretty(&value).map_err(|e| e.to_string())?;
+        let mut unset = vec![];
+        match serde_json::to_value(&value) {
+            Ok(json) => unset_keys("", &json, &mut unset),
+            Err(e) => return Err(e.to_string()),
+        }
+        let mut lines = text.lines().map(str::to_string).collect::<Vec<_>>();
+        for (table, key) in unset {
+            let start = match table.as_str() {
+                "" => Some(0),
+                table => lines
+                    .iter()
+                    .position(|l| *l == format!("[{table}]"))
+                    .map(|i| i + 1),
+            };
+            let Some(start) = start else {
+                continue;
+            };
+            // the end of the table, before the blank line ahead of the next header
+            let mut end = lines[start..]
+                .iter()
+                .position(|l| l.starts_with('['))
+                .map_or(lines.len(), |i| start + i);
+            while end > start && lines[end - 1].is_empty() {
+                end -= 1;
+            }
+            lines.insert(end, format!("# {key} ="));
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// write `example` to `path`, as json when it ends with `.json`
+    pub fn write_example(path: &str) -> Result<(), String> {
+        let text = if path.ends_with(".json") {
+            serde_json::to_string_pretty(&T::default()).map_err(|e| e.to_string())?
+        } else {
+            Self::example()?
+        };
+        std::fs::write(path, text).map_err(|e| e.to_string())
+    }
+}
+
+/// tables and keys of the nulls in `value`, the `None` fields toml leaves out
+fn unset_keys(table: &str, value: &serde_json::Value, unset: &mut Vec<(String, String)>) {
+    if let serde_json::Value::Object(map) = value {
+        for (k, v) in map {
+            match v {
+                serde_json::Value::Null => unset.push((table.to_string(), k.clone())),
+                serde_json::Value::Object(_) if table.is_empty() => unset_keys(k, v, unset),
+                serde_json::Value::Object(_) => unset_keys(&format!("{table}.{k}"), v, unset),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// `app` -> `app.prod`, `app.yaml` -> `app.prod.yaml`
+fn profile_name(name: &str, profile: &str) -> String {
+    let path = std::path::Path::new(name);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if base::conf::CONFIG_EXTENSIONS.contains(&ext) => {
+            format!(
+                "{}.{}.{}",
+                &name[..name.len() - ext.len() - 1],
+                profile,
+                ext
+            )
+        }
+        _ => format!("{name}.{profile}"),
+    }
+}
+
+fn collect_sources(
+    key: &str,
+    value: &config::Value,
+    env_prefix: &str,
+    sources: &mut BTreeMap<String, String>,
+) {
+    match value.clone().into_table() {
+        Ok(table) => {
+            for (k, v) in table.iter() {
+                let path = if key.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{key}.{k}")
+                };
+                collect_sources(&path, v, env_prefix, sources);
+            }
+        }
+        Err(_) => {
+            let source = match value.origin() {
+                None => "defaults".to_string(),
+                Some("the environment") => format!(
+                    "env {}__{}",
+                    env_prefix,
+                    key.replace('.', "__").to_uppercase()
+                ),
+                Some(file) => file.to_string(),
+            };
+            sources.insert(key.to_string(), source);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,12 +658,299 @@ mod tests {
         version: String,
     }
 
+    /// the global logger shared by the tests of this process
+    fn init_log() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            Log::new().redact(Redactor::default()).init_file().unwrap();
+        });
+    }
+
     #[test]
     fn test_log() {
         use std::{thread, time::Duration};
-        Log::new().init_file().unwrap();
+        init_log();
         log::info!("init log ...");
         thread::sleep(Duration::from_secs(1));
+
+        // a failed init leaves the directives of the installed logger alone
+        let directives = base::logs::LOG_DIRECTIVES.read().unwrap().clone();
+        assert!(Log::new().directives("rskit=error").init().is_err());
+        assert_eq!(directives, *base::logs::LOG_DIRECTIVES.read().unwrap());
+
+        // capturing works with the logger installed above
+        let capture = Log::new().init_capture().unwrap();
+        log::error!("request error: timeout");
+        log::warn!(target: "rskit::base::conf", "unknown key token=abc");
+        assert!(capture.contains(log::Level::Error, "timeout"));
+        assert_eq!(1, capture.by_target("rskit::base::conf").len());
+        assert_eq!(
+            "unknown key token=***",
+            capture.by_target("rskit::base")[0].message
+        );
+
+        // other threads and dropped guards don't record
+        thread::spawn(|| log::error!("elsewhere")).join().unwrap();
+        let records = LogCapture::clone(&capture);
+        drop(capture);
+        log::error!("after drop");
+        assert_eq!(2, records.records().len());
+        let capture = Log::new().init_capture().unwrap();
+        assert!(capture.records().is_empty());
+    }
+
+    #[test]
+    fn test_log_config() {
+        let toml = r#"
+            [log]
+            level = "warn"
+            rolling = "daily"
+            max_size = "50MB"
+            keep_days = 30
+            compress = "zstd"
+            redact_keys = ["pin"]
+        "#;
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        let config = config.get::<LogConfig>("log").unwrap();
+        let log = Log::new().with_config(&config).unwrap();
+        assert_eq!(LevelFilter::Warn, log.level);
+        assert_eq!(Some(Period::Daily), log.rolling.as_ref().unwrap().period);
+        assert_eq!(
+            Some(50 * 1024 * 1024),
+            log.rolling.as_ref().unwrap().size.map(|s| s.len())
+        );
+        assert!(matches!(log.keep, KeepType::KeepTime(d) if d.as_secs() == 30 * 24 * 3600));
+        assert_eq!(Some(Compress::Zstd), log.compress);
+        let redactor = log.redactor.unwrap();
+        assert!(redactor.is_sensitive("pin") && redactor.is_sensitive("password"));
+
+        let config = LogConfig {
+            max_size: Some("big".to_string()),
+            ..Default::default()
+        };
+        assert!(Log::new().with_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_log_from_settings() {
+        let toml = r#"
+            [app]
+            version = "0.0.1"
+
+            [log]
+            path = "logs/api.log"
+            console = false
+            format = "json"
+            level = "debug"
+            directives = "hyper=warn"
+            rolling = "hourly"
+            keep_num = 24
+            compress = "gzip"
+        "#;
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize::<crate::conf::Settings>()
+            .unwrap();
+        let log = Log::from_settings(&RwLock::new(Some(settings))).unwrap();
+        assert_eq!("logs/api.log", log.path);
+        assert!(!log.console);
+        assert_eq!(LogFormat::Json, log.format);
+        assert_eq!(LevelFilter::Debug, log.level);
+        assert_eq!(Some("hyper=warn".to_string()), log.directives);
+        assert_eq!(Some(Period::Hourly), log.rolling.as_ref().unwrap().period);
+        assert_eq!(None, log.rolling.as_ref().unwrap().size.map(|s| s.len()));
+        assert!(matches!(log.keep, KeepType::KeepNum(24)));
+        assert_eq!(Some(Compress::Gzip), log.compress);
+
+        let log = Log::from_settings(&RwLock::new(None)).unwrap();
+        assert_eq!(Log::new().path, log.path);
+        assert!(log.console);
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Layered {
+        app: App,
+        db: Db,
+    }
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Db {
+        host: String,
+        port: u16,
+        pool: u32,
+    }
+
+    #[test]
+    fn test_layered_config() {
+        let dir = std::env::temp_dir().join("rskit-layered");
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("app").to_str().unwrap().to_string();
+        std::fs::write(
+            format!("{name}.toml"),
+            "[app]\nversion = \"1.0.0\"\n[db]\nhost = \"localhost\"\n",
+        )
+        .unwrap();
+        std::fs::write(format!("{name}.prod.toml"), "[db]\nhost = \"db.prod\"\n").unwrap();
+        let env = config::Map::from([
+            ("APP_ENV".to_string(), "prod".to_string()),
+            ("RSKIT_LAYER__DB__PORT".to_string(), "6543".to_string()),
+        ]);
+
+        let mut configs = Configs::<Layered>::new()
+            .defaults("[db]\nhost = \"127.0.0.1\"\nport = 5432\npool = 10\n")
+            .env_prefix("RSKIT_LAYER")
+            .env(env)
+            .dotenv(false);
+        let config = configs.init(Some(name.clone())).unwrap();
+        assert_eq!("1.0.0", config.app.version);
+        assert_eq!("db.prod", config.db.host);
+        assert_eq!(6543, config.db.port);
+        assert_eq!(10, config.db.pool);
+
+        assert_eq!(Some("defaults"), configs.source("db.pool"));
+        assert_eq!(Some("env RSKIT_LAYER__DB__PORT"), configs.source("db.port"));
+        assert!(
+            configs
+                .source("db.host")
+                .unwrap()
+                .ends_with("app.prod.toml")
+        );
+        assert!(configs.source("app.version").unwrap().ends_with("app.toml"));
+    }
+
+    #[test]
+    fn test_secret_config() {
+        use crate::crypto::secret::{encrypt_secret, gen_master_key};
+        use base64::{Engine, prelude::BASE64_STANDARD};
+        let key = BASE64_STANDARD.decode(gen_master_key()).unwrap();
+        let dir = std::env::temp_dir().join("rskit-secret");
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("app").to_str().unwrap().to_string();
+        std::fs::write(
+            format!("{name}.toml"),
+            format!(
+                "[app]\nversion = \"{}\"\n",
+                encrypt_secret(&key, "1.0.0").unwrap()
+            ),
+        )
+        .unwrap();
+
+        let mut configs = Configs::<Settings>::new().dotenv(false).master_key(&key);
+        let config = configs.init(Some(name.clone())).unwrap();
+        assert_eq!("1.0.0", config.app.version);
+
+        let other = BASE64_STANDARD.decode(gen_master_key()).unwrap();
+        let mut configs = Configs::<Settings>::new().dotenv(false).master_key(&other);
+        assert!(configs.init(Some(name.clone())).is_none());
+
+        // the key comes with the injected variables
+        let env = config::Map::from([("APP_MASTER_KEY".to_string(), BASE64_STANDARD.encode(&key))]);
+        let mut configs = Configs::<Settings>::new().dotenv(false).env(env);
+        assert_eq!("1.0.0", configs.init(Some(name)).unwrap().app.version);
+    }
+
+    #[test]
+    fn test_multi_format_config() {
+        let dir = std::env::temp_dir().join("rskit-formats");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let path = |p: &str| dir.join(p).to_str().unwrap().to_string();
+        std::fs::write(
+            path("app.yaml"),
+            "app:\n  version: 1.0.0\ndb:\n  host: localhost\n  port: 5432\n  pool: 1\n",
+        )
+        .unwrap();
+        std::fs::write(path("app.dev.yaml"), "db:\n  host: db.dev\n").unwrap();
+        std::fs::write(path("extra.json"), r#"{"db": {"pool": 2, "port": 6000}}"#).unwrap();
+        std::fs::write(path("conf.d/10-db.toml"), "[db]\npool = 3\n").unwrap();
+        std::fs::write(path("conf.d/20-db.json"), r#"{"db": {"pool": 4}}"#).unwrap();
+        std::fs::write(path("conf.d/notes.txt"), "pool = 5").unwrap();
+        std::fs::write(path("plain"), r#"{"app": {"version": "2.0.0"}}"#).unwrap();
+
+        let mut configs = Configs::<Layered>::new()
+            .dotenv(false)
+            .profile("dev")
+            .file(&path("extra.json"))
+            .dir(&path("conf.d"));
+        let config = configs.init(Some(path("app"))).unwrap();
+        assert_eq!("1.0.0", config.app.version);
+        assert_eq!("db.dev", config.db.host);
+        assert_eq!(6000, config.db.port);
+        assert_eq!(4, config.db.pool);
+        assert!(configs.source("db.pool").unwrap().ends_with("20-db.json"));
+
+        let mut configs = Configs::<Layered>::new().dotenv(false).profile("dev");
+        let config = configs.init(Some(path("app.yaml"))).unwrap();
+        assert_eq!("db.dev", config.db.host);
+
+        let mut configs = Configs::<Settings>::new()
+            .dotenv(false)
+            .format(config::FileFormat::Json);
+        let config = configs.init(Some(path("plain"))).unwrap();
+        assert_eq!("2.0.0", config.app.version);
+    }
+
+    #[test]
+    fn test_strict_config() {
+        let dir = std::env::temp_dir().join("rskit-strict");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.toml");
+        std::fs::write(
+            &path,
+            "[app]\nversion = \"1.0.0\"\n\n[db]\nhost = \"localhost\"\nport = 5432\npool = 1\npoool = 2\n",
+        )
+        .unwrap();
+        let name = dir.join("app").to_str().unwrap().to_string();
+
+        init_log();
+        let capture = Log::new().init_capture().unwrap();
+        let mut configs = Configs::<Layered>::new().dotenv(false);
+        assert!(configs.init(Some(name.clone())).is_some());
+        assert_eq!(1, configs.unknown_keys().len());
+        assert!(!capture.contains(log::Level::Warn, "db.poool"));
+
+        let mut configs = Configs::<Layered>::new().dotenv(false).strict(true);
+        assert!(configs.init(Some(name)).is_none());
+        assert!(capture.contains(log::Level::Warn, "db.poool"));
+        let unknown = &configs.unknown_keys()[0];
+        assert_eq!("db.poool", unknown.key);
+        assert_eq!(Some(8), unknown.line);
+        assert!(unknown.to_string().ends_with("app.toml:8"));
+    }
+
+    #[test]
+    fn test_example_config() {
+        let example = Configs::<Layered>::example().unwrap();
+        assert!(example.contains("[app]") && example.contains("[db]"));
+        assert!(example.contains("port = 0"));
+        let parsed: Layered = toml::from_str(&example).unwrap();
+        assert_eq!(Layered::default().db.port, parsed.db.port);
+
+        // unset options show up commented out, in their own table
+        let example = Configs::<base::conf::Settings>::example().unwrap();
+        assert_eq!("# log =\n[app]\nversion = \"\"\n", example);
+        #[derive(Default, Serialize, Deserialize)]
+        struct Optional {
+            name: Option<String>,
+            db: Pool,
+        }
+        #[derive(Default, Serialize, Deserialize)]
+        struct Pool {
+            size: u32,
+            timeout: Option<u64>,
+        }
+        let example = Configs::<Optional>::example().unwrap();
+        assert_eq!("# name =\n[db]\nsize = 0\n# timeout =\n", example);
+        assert!(toml::from_str::<Optional>(&example).is_ok());
+
+        let path = std::env::temp_dir().join("rskit-app.example.json");
+        Configs::<Layered>::write_example(path.to_str().unwrap()).unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        assert!(serde_json::from_str::<Layered>(&text).is_ok());
     }
 
     #[test]
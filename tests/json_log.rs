@@ -0,0 +1,37 @@
+//! The global logger can be installed once per process, so this runs in its own test binary.
+
+use rskit::{Log, log::Log as _, logs::LogFormat};
+use serde_json::Value;
+
+#[test]
+fn test_json_log() {
+    let path = std::env::temp_dir().join("rskit-json-log.log");
+    let _ = std::fs::remove_file(&path);
+    let log = Log {
+        path: path.to_str().unwrap().to_string(),
+        console: false,
+        directives: None,
+        ..Log::new().format(LogFormat::Json).field("service", "kit")
+    };
+    log.init_file().unwrap();
+    rskit::log::info!(user = "bob", attempt = 3, ok = true; "login \"quoted\"\nnext line");
+    rskit::log::warn!(target: "rskit::base::db", "slow query");
+    rskit::log::logger().flush();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = text
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(2, lines.len());
+    assert_eq!("login \"quoted\"\nnext line", lines[0]["message"]);
+    assert_eq!("INFO", lines[0]["level"]);
+    assert_eq!("bob", lines[0]["user"]);
+    assert_eq!(3, lines[0]["attempt"]);
+    assert_eq!(true, lines[0]["ok"]);
+    assert_eq!("kit", lines[0]["service"]);
+    assert!(lines[0]["time"].as_str().unwrap().contains('T'));
+    assert_eq!("rskit::base::db", lines[1]["target"]);
+    assert_eq!("WARN", lines[1]["level"]);
+}
@@ -0,0 +1,58 @@
+//! The global logger can be installed once per process, so this runs in its own test binary.
+
+use std::time::Duration;
+
+use rskit::{
+    Log,
+    fast_log::{
+        consts::LogSize,
+        plugin::{
+            file_split::{KeepType, Rolling, RollingType},
+            packer::LogPacker,
+        },
+    },
+    log::Log as _,
+};
+
+#[test]
+fn test_split_log() {
+    let dir = std::env::temp_dir().join("rskit-split-log");
+    let _ = std::fs::remove_dir_all(&dir);
+    let log = Log {
+        path: format!("{}/", dir.to_str().unwrap()) + "app.log",
+        console: false,
+        directives: None,
+        // the fast_log types set directly still roll and pack
+        roll: Rolling::new(RollingType::BySize(LogSize::KB(1))),
+        keep: KeepType::KeepNum(100),
+        packer: LogPacker {},
+        ..Log::new()
+    };
+    log.init_split().unwrap();
+    for i in 0..100 {
+        rskit::log::info!("line {i} to fill the log past its size limit");
+    }
+    rskit::log::logger().flush();
+
+    let mut rolled = vec![];
+    for _ in 0..50 {
+        rolled = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name != "app.log")
+            .collect();
+        if !rolled.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!rolled.is_empty());
+    assert!(
+        rolled
+            .iter()
+            .all(|name| name.starts_with("app") && name.ends_with(".log"))
+    );
+    let text = std::fs::read_to_string(dir.join(&rolled[0])).unwrap();
+    assert!(text.contains("to fill the log"));
+}